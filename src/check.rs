@@ -0,0 +1,653 @@
+use crate::custom;
+use crate::error::{Error, Result};
+use crate::ser::{
+    check_dense_int_keys, check_newtype_variant_content, check_variant_key, duplicate_key,
+    is_nil_string, is_userdata_struct, nested_depth, NonePlaceholder, ScalarKey, SerializerOptions,
+};
+use serde::{ser, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+
+/// Checks that `value` would serialize with `options` without error, without creating any Lua
+/// values, e.g. to fail fast before handing data to Lua.
+///
+/// Performs the same checks as [`to_value_with`](crate::to_value_with) (depth, enum keys, table
+/// keys, duplicate and sparse keys, ...), except for `strict_float_precision`, which depends on
+/// the Lua build.
+pub fn can_serialize<T>(value: &T, options: &SerializerOptions) -> Result<()>
+where
+    T: ?Sized + Serialize,
+{
+    value
+        .serialize(Checker {
+            options,
+            map_key: false,
+            in_seq: false,
+            depth: 0,
+        })
+        .map(drop)
+}
+
+/// What a value would serialize to, as far as the checks need to know. Only map keys keep their
+/// value, to detect duplicates.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Checked {
+    Nil,
+    NaN,
+    Int(i64),
+    Float(u64),
+    Bool(bool),
+    Str(String),
+    Table,
+    Other,
+}
+
+impl Checked {
+    fn number(v: f64) -> Self {
+        if v.is_nan() {
+            Checked::NaN
+        } else if v.fract() == 0.0 && v >= i64::MIN as f64 && v < i64::MAX as f64 {
+            // Lua doesn't distinguish `1` and `1.0` as table keys
+            Checked::Int(v as i64)
+        } else {
+            Checked::Float(v.to_bits())
+        }
+    }
+}
+
+impl fmt::Display for Checked {
+    // Renders keys like `key_to_string` does.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Checked::Nil => f.write_str("<nil>"),
+            Checked::NaN => f.write_str("NaN"),
+            Checked::Int(i) => write!(f, "{}", i),
+            Checked::Float(bits) => write!(f, "{}", f64::from_bits(*bits)),
+            Checked::Bool(b) => write!(f, "{}", b),
+            Checked::Str(s) => f.write_str(s),
+            Checked::Table => f.write_str("<table>"),
+            Checked::Other => f.write_str("<userdata>"),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Checker<'a> {
+    options: &'a SerializerOptions,
+    map_key: bool,
+    in_seq: bool,
+    depth: usize,
+}
+
+impl<'a> Checker<'a> {
+    fn nested(self) -> Result<Self> {
+        let depth = nested_depth(self.depth, self.options)?;
+        Ok(Checker {
+            depth,
+            in_seq: false,
+            ..self
+        })
+    }
+
+    fn check_variant_key(&self, variant: &str) -> Result<()> {
+        check_variant_key(variant, self.map_key, self.options)
+    }
+
+    fn str(&self, v: &str) -> Checked {
+        if self.map_key {
+            Checked::Str(v.to_string())
+        } else {
+            Checked::Other
+        }
+    }
+}
+
+impl<'a> ser::Serializer for Checker<'a> {
+    type Ok = Checked;
+    type Error = Error;
+
+    type SerializeSeq = SeqChecker<'a>;
+    type SerializeTuple = SeqChecker<'a>;
+    type SerializeTupleStruct = SeqChecker<'a>;
+    type SerializeTupleVariant = SeqChecker<'a>;
+    type SerializeMap = MapChecker<'a>;
+    type SerializeStruct = MapChecker<'a>;
+    type SerializeStructVariant = MapChecker<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<Checked> {
+        if self.options.bool_as_int {
+            return Ok(Checked::Int(v as i64));
+        }
+        Ok(Checked::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Checked> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Checked> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Checked> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Checked> {
+        Ok(Checked::Int(v))
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<Checked> {
+        Ok(self.str(&v.to_string()))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Checked> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Checked> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Checked> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Checked> {
+        if v > i64::MAX as u64 {
+            return self.serialize_f64(v as f64);
+        }
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<Checked> {
+        Ok(self.str(&v.to_string()))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Checked> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Checked> {
+        Ok(Checked::number(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Checked> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Checked> {
+        if is_nil_string(v, self.map_key || self.in_seq, self.options) {
+            return Ok(Checked::Nil);
+        }
+        Ok(self.str(v))
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<Checked> {
+        self.nested()?;
+        Ok(Checked::Table)
+    }
+
+    fn serialize_none(self) -> Result<Checked> {
+        Ok(Checked::Nil)
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<Checked>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Checked> {
+        Ok(Checked::Nil)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Checked> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Checked> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T>(self, name: &'static str, value: &T) -> Result<Checked>
+    where
+        T: ?Sized + Serialize,
+    {
+        if name == custom::TOKEN {
+            if let Some(value) = custom::take() {
+                if self.options.custom.contains(value.as_ref()) {
+                    // the registered serializer needs a Lua state, so its result isn't known
+                    return Ok(Checked::Other);
+                }
+            }
+        }
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Checked>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.check_variant_key(variant)?;
+        let value = value.serialize(self)?;
+        check_newtype_variant_content(variant, value == Checked::Table, self.options)?;
+        Ok(Checked::Table)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<SeqChecker<'a>> {
+        Ok(SeqChecker {
+            ser: Checker {
+                in_seq: true,
+                ..self.nested()?
+            },
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SeqChecker<'a>> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<SeqChecker<'a>> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SeqChecker<'a>> {
+        self.check_variant_key(variant)?;
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<MapChecker<'a>> {
+        Ok(MapChecker {
+            ser: self.nested()?,
+            keys: HashSet::new(),
+            key: None,
+            userdata: false,
+        })
+    }
+
+    fn serialize_struct(self, name: &'static str, len: usize) -> Result<MapChecker<'a>> {
+        let userdata = is_userdata_struct(name, self.depth, self.options);
+        let mut map = self.serialize_map(Some(len))?;
+        map.userdata = userdata;
+        Ok(map)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<MapChecker<'a>> {
+        self.check_variant_key(variant)?;
+        self.serialize_map(Some(len))
+    }
+
+    fn is_human_readable(&self) -> bool {
+        self.options.human_readable
+    }
+}
+
+struct SeqChecker<'a> {
+    ser: Checker<'a>,
+}
+
+impl<'a> SeqChecker<'a> {
+    fn element<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(self.ser).map(drop)
+    }
+}
+
+impl<'a> ser::SerializeSeq for SeqChecker<'a> {
+    type Ok = Checked;
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<Checked> {
+        Ok(Checked::Table)
+    }
+}
+
+impl<'a> ser::SerializeTuple for SeqChecker<'a> {
+    type Ok = Checked;
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<Checked> {
+        Ok(Checked::Table)
+    }
+}
+
+impl<'a> ser::SerializeTupleStruct for SeqChecker<'a> {
+    type Ok = Checked;
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<Checked> {
+        Ok(Checked::Table)
+    }
+}
+
+impl<'a> ser::SerializeTupleVariant for SeqChecker<'a> {
+    type Ok = Checked;
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<Checked> {
+        Ok(Checked::Table)
+    }
+}
+
+struct MapChecker<'a> {
+    ser: Checker<'a>,
+    /// The keys stored so far (i.e. with a non-nil value).
+    keys: HashSet<Checked>,
+    key: Option<Checked>,
+    /// Whether the struct ends up in a `StructHandle`.
+    userdata: bool,
+}
+
+impl<'a> MapChecker<'a> {
+    fn insert(&mut self, key: Checked, value: Checked) -> Result<()> {
+        match key {
+            Checked::Nil => return Err(Error::Message("table index is nil".to_string())),
+            Checked::NaN => return Err(Error::Message("table index is NaN".to_string())),
+            _ => {}
+        }
+        if value == Checked::Nil && self.ser.options.none_placeholder == NonePlaceholder::Omit {
+            return Ok(());
+        }
+        if matches!(key, Checked::Table | Checked::Other) {
+            // every table is a distinct key
+            return Ok(());
+        }
+        if !self.keys.insert(key.clone()) && self.ser.options.error_on_duplicate_keys {
+            return Err(duplicate_key(&key.to_string()));
+        }
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeMap for MapChecker<'a> {
+    type Ok = Checked;
+    type Error = Error;
+
+    fn serialize_key<T>(&mut self, key: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        let key = match key.serialize(Checker {
+            map_key: true,
+            ..self.ser
+        })? {
+            Checked::Int(i) => ScalarKey::Int(i),
+            Checked::Float(bits) => ScalarKey::Float(f64::from_bits(bits)),
+            Checked::NaN => ScalarKey::Float(f64::NAN),
+            Checked::Bool(b) => ScalarKey::Bool(b),
+            key => {
+                self.key.replace(key);
+                return Ok(());
+            }
+        };
+        let key = match key.normalize(self.ser.options)? {
+            Ok(ScalarKey::Int(i)) => Checked::Int(i),
+            Ok(ScalarKey::Float(n)) => Checked::number(n),
+            Ok(ScalarKey::Bool(b)) => Checked::Bool(b),
+            Err(s) => Checked::Str(s),
+        };
+        self.key.replace(key);
+        Ok(())
+    }
+
+    fn serialize_value<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        let value = value.serialize(self.ser)?;
+        let key = self
+            .key
+            .take()
+            .expect("serialize_key must be called before serialize_value");
+        self.insert(key, value)
+    }
+
+    fn end(self) -> Result<Checked> {
+        let keys = self.keys.iter().filter_map(|key| match key {
+            Checked::Int(i) => Some(*i),
+            _ => None,
+        });
+        check_dense_int_keys(keys, self.ser.options)?;
+        Ok(Checked::Table)
+    }
+}
+
+impl<'a> ser::SerializeStruct for MapChecker<'a> {
+    type Ok = Checked;
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        let value = value.serialize(self.ser)?;
+        self.insert(Checked::Str(key.to_string()), value)
+    }
+
+    fn end(self) -> Result<Checked> {
+        let userdata = self.userdata;
+        ser::SerializeMap::end(self)?;
+        Ok(if userdata {
+            Checked::Other
+        } else {
+            Checked::Table
+        })
+    }
+}
+
+impl<'a> ser::SerializeStructVariant for MapChecker<'a> {
+    type Ok = Checked;
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, _key: &'static str, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(self.ser).map(drop)
+    }
+
+    fn end(self) -> Result<Checked> {
+        Ok(Checked::Table)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::can_serialize;
+    use crate::{to_value_with, Custom, EnumRepresentation, SerializerOptions};
+    use mlua::{Lua, Value};
+    use serde::{ser, Serialize, Serializer};
+    use std::collections::HashMap;
+
+    #[derive(Serialize)]
+    struct Reading {
+        sensor: &'static str,
+        values: Vec<f64>,
+    }
+
+    #[test]
+    fn passes_serializable_values() {
+        let reading = Reading {
+            sensor: "a",
+            values: vec![1.5, f64::INFINITY, f64::NAN],
+        };
+        assert!(can_serialize(&reading, &SerializerOptions::default()).is_ok());
+
+        let options = SerializerOptions {
+            max_depth: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            "maximum depth of 1 exceeded",
+            can_serialize(&reading, &options).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn rejects_non_finite_keys() {
+        let mut map = HashMap::new();
+        map.insert(ordered_float::OrderedFloat(f64::NAN), 1);
+        let err = can_serialize(&map, &SerializerOptions::default()).unwrap_err();
+        assert_eq!("table index is NaN", err.to_string());
+
+        let mut map = HashMap::new();
+        map.insert(ordered_float::OrderedFloat(f64::INFINITY), 1);
+        assert!(can_serialize(&map, &SerializerOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn honors_custom_serializers() {
+        #[derive(Clone)]
+        struct Handle;
+
+        impl Serialize for Handle {
+            fn serialize<S>(&self, _serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: Serializer,
+            {
+                Err(ser::Error::custom("handles can't be serialized"))
+            }
+        }
+
+        let mut options = SerializerOptions::default();
+        assert_eq!(
+            "handles can't be serialized",
+            can_serialize(&Custom(Handle), &options)
+                .unwrap_err()
+                .to_string()
+        );
+        options.register_custom(|_: &Handle, _| Ok(Value::Nil));
+        assert!(can_serialize(&Custom(Handle), &options).is_ok());
+    }
+
+    #[test]
+    fn agrees_with_serializer() {
+        fn assert_agrees<T: Serialize>(value: &T, options: SerializerOptions) {
+            let lua = Lua::new();
+            let expected = to_value_with(&lua, value, options.clone())
+                .map(drop)
+                .map_err(|err| err.to_string());
+            let checked = can_serialize(value, &options).map_err(|err| err.to_string());
+            assert_eq!(expected, checked);
+        }
+
+        #[derive(Serialize)]
+        enum Shape {
+            Circle(f64),
+            Segment(Option<f64>, Option<f64>),
+        }
+
+        let sparse: HashMap<i64, u8> = [(1, 1), (3, 3)].iter().copied().collect();
+        let bools: HashMap<bool, u8> = [(true, 1), (false, 0)].iter().copied().collect();
+        let empty: HashMap<&str, &str> = [("", "")].iter().copied().collect();
+        for options in &[
+            SerializerOptions::default(),
+            SerializerOptions {
+                reject_sparse_int_keys: true,
+                ..Default::default()
+            },
+            SerializerOptions {
+                reject_sparse_int_keys: true,
+                int_key_offset: -1,
+                zero_based_arrays: true,
+                ..Default::default()
+            },
+            SerializerOptions {
+                reject_sparse_int_keys: true,
+                stringify_map_keys: true,
+                ..Default::default()
+            },
+            SerializerOptions {
+                bool_as_int: true,
+                error_on_duplicate_keys: true,
+                ..Default::default()
+            },
+            SerializerOptions {
+                empty_string_as_nil: true,
+                enum_keys_as_string: true,
+                ..Default::default()
+            },
+            SerializerOptions {
+                preserve_seq_holes: true,
+                zero_based_arrays: true,
+                ..Default::default()
+            },
+            SerializerOptions {
+                enum_representation: EnumRepresentation::InternallyTagged {
+                    tag: "type".to_string(),
+                },
+                max_depth: Some(1),
+                ..Default::default()
+            },
+        ] {
+            assert_agrees(&sparse, options.clone());
+            assert_agrees(&bools, options.clone());
+            assert_agrees(&empty, options.clone());
+            assert_agrees(&Shape::Circle(1.0), options.clone());
+            assert_agrees(&Shape::Segment(None, Some(1.0)), options.clone());
+            assert_agrees(&vec![vec![1]], options.clone());
+        }
+
+        let max: HashMap<i64, u8> = [(i64::MAX, 1)].iter().copied().collect();
+        let options = SerializerOptions {
+            int_key_offset: 1,
+            ..Default::default()
+        };
+        assert_agrees(&max, options);
+    }
+}
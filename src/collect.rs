@@ -0,0 +1,456 @@
+use crate::de::Deserializer;
+use crate::error::{Error, Result};
+use mlua::Value;
+use serde::de::{self, DeserializeSeed, IntoDeserializer, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Deserializes `value` like [`from_value`](crate::from_value), but instead of stopping at the
+/// first error, reports the errors of all values that fail to deserialize, each prefixed with
+/// its path (e.g. `` `server.port`: ... ``).
+///
+/// Deserialization is repeated, each time replacing the values that failed before with a
+/// placeholder (zero, empty, `None`, ...), until no new error turns up. Only the first missing
+/// field of each struct is reported.
+pub fn from_value_collect_errors<'lua, T>(value: Value<'lua>) -> std::result::Result<T, Vec<Error>>
+where
+    T: Deserialize<'lua>,
+{
+    let mut errors = Vec::new();
+    let mut skip = Vec::new();
+    loop {
+        let collector = Rc::new(ErrorCollector {
+            path: RefCell::new(Vec::new()),
+            skip: skip.clone(),
+        });
+        let deserializer = Deserializer::with_error_collector(value.clone(), collector.clone());
+        let err = match T::deserialize(deserializer) {
+            Ok(t) if errors.is_empty() => return Ok(t),
+            Ok(_) => return Err(errors),
+            Err(err) => err,
+        };
+
+        let path = collector.path.take();
+        if path.is_empty() {
+            errors.push(err);
+            return Err(errors);
+        }
+        if skip.contains(&path) {
+            // the placeholder didn't fit either, so there is no way to continue
+            return Err(errors);
+        }
+        errors.push(Error::WithPath {
+            path: path.join("."),
+            error: Box::new(err),
+        });
+        skip.push(path);
+    }
+}
+
+/// Deserializes `value` like [`from_value`](crate::from_value), prefixing the error with the path
+/// of the value that failed.
+pub(crate) fn from_value_with_path<'lua, T>(value: Value<'lua>) -> Result<T>
+where
+    T: Deserialize<'lua>,
+{
+    let collector = Rc::new(ErrorCollector {
+        path: RefCell::new(Vec::new()),
+        skip: Vec::new(),
+    });
+    let deserializer = Deserializer::with_error_collector(value, collector.clone());
+    T::deserialize(deserializer).map_err(|err| {
+        let path = collector.path.take();
+        if path.is_empty() {
+            return err;
+        }
+        Error::WithPath {
+            path: path.join("."),
+            error: Box::new(err),
+        }
+    })
+}
+
+/// Tracks the path of the value being deserialized, see [`from_value_collect_errors`].
+#[derive(Debug)]
+pub struct ErrorCollector {
+    path: RefCell<Vec<String>>,
+    /// Paths of values that failed in a previous attempt.
+    skip: Vec<Vec<String>>,
+}
+
+impl ErrorCollector {
+    /// Enters the value at `segment`, returns whether it should be replaced with a placeholder.
+    /// The segment is left again with [`leave`](Self::leave) once the value deserialized
+    /// successfully, so that on error `path` points to the failing value.
+    fn enter(&self, segment: String) -> bool {
+        let mut path = self.path.borrow_mut();
+        path.push(segment);
+        self.skip.contains(&path)
+    }
+
+    fn leave(&self) {
+        self.path.borrow_mut().pop();
+    }
+
+    /// Deserializes the value at `segment` with `seed`, either from `deserializer` or from a
+    /// placeholder.
+    pub(crate) fn deserialize<'de, T, D>(
+        &self,
+        segment: String,
+        seed: T,
+        deserializer: D,
+    ) -> Result<T::Value>
+    where
+        T: DeserializeSeed<'de>,
+        D: de::Deserializer<'de, Error = Error>,
+    {
+        let value = if self.enter(segment) {
+            seed.deserialize(Placeholder)?
+        } else {
+            seed.deserialize(deserializer)?
+        };
+        self.leave();
+        Ok(value)
+    }
+}
+
+macro_rules! forward_placeholder {
+    ($($($method:ident)* => $target:ident)*) => {
+        $($(
+            fn $method<V>(self, visitor: V) -> Result<V::Value>
+            where
+                V: Visitor<'de>,
+            {
+                self.$target(visitor)
+            }
+        )*)*
+    };
+}
+
+/// Produces an arbitrary value of whatever type is requested, e.g. `0`, `""`, `None` or a struct
+/// whose fields are placeholders themselves.
+struct Placeholder;
+
+impl<'de> de::Deserializer<'de> for Placeholder {
+    type Error = Error;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_bool<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_bool(false)
+    }
+
+    fn deserialize_i64<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_i64(0)
+    }
+
+    fn deserialize_u64<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_u64(0)
+    }
+
+    fn deserialize_f64<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_f64(0.0)
+    }
+
+    fn deserialize_char<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_char('\0')
+    }
+
+    fn deserialize_str<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_str("")
+    }
+
+    fn deserialize_bytes<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_bytes(&[])
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_none()
+    }
+
+    fn deserialize_newtype_struct<V>(self, _name: &'static str, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_seq(PlaceholderSeq(0))
+    }
+
+    fn deserialize_tuple<V>(self, len: usize, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_seq(PlaceholderSeq(len))
+    }
+
+    fn deserialize_tuple_struct<V>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_map<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_map(PlaceholderStruct(&[]))
+    }
+
+    fn deserialize_struct<V>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_map(PlaceholderStruct(fields))
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        _name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        match variants.first() {
+            Some(variant) => visitor.visit_enum(PlaceholderEnum(variant)),
+            None => Err(de::Error::custom("enum without variants")),
+        }
+    }
+
+    fn deserialize_unit_struct<V>(self, _name: &'static str, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_unit()
+    }
+
+    forward_placeholder! {
+        deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i128 => deserialize_i64
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u128 => deserialize_u64
+        deserialize_f32 => deserialize_f64
+        deserialize_string deserialize_identifier => deserialize_str
+        deserialize_byte_buf => deserialize_bytes
+        deserialize_unit deserialize_ignored_any => deserialize_any
+    }
+}
+
+struct PlaceholderSeq(usize);
+
+impl<'de> SeqAccess<'de> for PlaceholderSeq {
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        if self.0 == 0 {
+            return Ok(None);
+        }
+        self.0 -= 1;
+        seed.deserialize(Placeholder).map(Some)
+    }
+}
+
+struct PlaceholderStruct(&'static [&'static str]);
+
+impl<'de> MapAccess<'de> for PlaceholderStruct {
+    type Error = Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>>
+    where
+        K: DeserializeSeed<'de>,
+    {
+        match self.0.split_first() {
+            Some((field, rest)) => {
+                self.0 = rest;
+                seed.deserialize(IntoDeserializer::<Error>::into_deserializer(*field))
+                    .map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        seed.deserialize(Placeholder)
+    }
+}
+
+struct PlaceholderEnum(&'static str);
+
+impl<'de> de::EnumAccess<'de> for PlaceholderEnum {
+    type Error = Error;
+    type Variant = Placeholder;
+
+    fn variant_seed<V>(self, seed: V) -> Result<(V::Value, Placeholder)>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        let variant = seed.deserialize(IntoDeserializer::<Error>::into_deserializer(self.0))?;
+        Ok((variant, Placeholder))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Placeholder {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        seed.deserialize(Placeholder)
+    }
+
+    fn tuple_variant<V>(self, len: usize, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        de::Deserializer::deserialize_tuple(self, len, visitor)
+    }
+
+    fn struct_variant<V>(self, fields: &'static [&'static str], visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        de::Deserializer::deserialize_struct(self, "", fields, visitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::from_value_collect_errors;
+    use mlua::Lua;
+    use serde::Deserialize;
+    use std::collections::HashMap;
+
+    #[derive(Deserialize, PartialEq, Debug)]
+    struct Server {
+        host: String,
+        port: u16,
+    }
+
+    #[derive(Deserialize, PartialEq, Debug)]
+    struct Config {
+        name: String,
+        servers: Vec<Server>,
+        retries: Option<u8>,
+    }
+
+    #[test]
+    fn collects_all_errors() {
+        let lua = Lua::new();
+        let value = lua
+            .load(
+                r#"return {
+                    name = 1,
+                    servers = { { host = "a", port = 80 }, { host = "b", port = "http" } },
+                    retries = -1,
+                }"#,
+            )
+            .eval()
+            .unwrap();
+        let errors = from_value_collect_errors::<Config>(value).unwrap_err();
+        let mut errors: Vec<String> = errors.into_iter().map(|err| err.to_string()).collect();
+        errors.sort();
+        assert_eq!(
+            vec![
+                "`name`: invalid type: integer `1`, expected a string",
+                "`retries`: invalid value: integer `-1`, expected u8",
+                "`servers.2.port`: invalid type: string \"http\", expected u16",
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn missing_fields_and_success() {
+        let lua = Lua::new();
+        let value = lua
+            .load(r#"return { servers = { { port = "x" } } }"#)
+            .eval()
+            .unwrap();
+        let errors = from_value_collect_errors::<Config>(value).unwrap_err();
+        let mut errors: Vec<String> = errors.into_iter().map(|err| err.to_string()).collect();
+        errors.sort();
+        assert_eq!(
+            vec![
+                "`servers.1.port`: invalid type: string \"x\", expected u16",
+                "`servers.1`: missing field `host`",
+                "missing field `name`",
+            ],
+            errors
+        );
+
+        let value = lua
+            .load(r#"return { name = "app", servers = { { host = "a", port = 80 } } }"#)
+            .eval()
+            .unwrap();
+        let config = from_value_collect_errors::<Config>(value).unwrap();
+        assert_eq!("app", config.name);
+    }
+
+    #[test]
+    fn non_string_keys_in_paths() {
+        let lua = Lua::new();
+        let value = lua.load(r#"return { [true] = "on" }"#).eval().unwrap();
+        let errors = from_value_collect_errors::<HashMap<bool, u8>>(value).unwrap_err();
+        assert_eq!(
+            "`true`: invalid type: string \"on\", expected u8",
+            errors[0].to_string()
+        );
+    }
+}
@@ -0,0 +1,198 @@
+use crate::error::Result;
+use mlua::{Lua, Value};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// Newtype name the [`Serializer`](crate::Serializer) recognizes to look up custom serializers.
+pub(crate) const TOKEN: &str = "$serde_mlua::private::Custom";
+
+thread_local! {
+    static CUSTOM_VALUE: RefCell<Option<Box<dyn Any>>> = const { RefCell::new(None) };
+}
+
+/// Opts a value into the serializers registered with
+/// [`SerializerOptions::register_custom`](crate::SerializerOptions::register_custom). Types
+/// without a registered serializer, and serializers other than this crate's, serialize the
+/// wrapped value as usual.
+///
+/// The serializer can't downcast the borrowed value it is handed, so the wrapped value is cloned
+/// to pass it on to the registered serializer.
+///
+/// ```
+/// use mlua::{Lua, Value};
+/// use serde::Serialize;
+/// use serde_mlua::{to_value_with, Custom, SerializerOptions};
+///
+/// #[derive(Clone, Serialize)]
+/// struct Color {
+///     r: u8,
+///     g: u8,
+///     b: u8,
+/// }
+///
+/// let mut options = SerializerOptions::default();
+/// options.register_custom(|c: &Color, _: &Lua| {
+///     Ok(Value::Integer(i64::from(c.r) << 16 | i64::from(c.g) << 8 | i64::from(c.b)))
+/// });
+///
+/// let lua = Lua::new();
+/// let color = Custom(Color { r: 0xff, g: 0x80, b: 0x00 });
+/// let value = to_value_with(&lua, &color, options).unwrap();
+/// assert_eq!(Value::Integer(0xff8000), value);
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Custom<T>(pub T);
+
+impl<T> Serialize for Custom<T>
+where
+    T: Serialize + Clone + 'static,
+{
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let value: Box<dyn Any> = Box::new(self.0.clone());
+        let previous = CUSTOM_VALUE.with(|slot| slot.replace(Some(value)));
+        let result = serializer.serialize_newtype_struct(TOKEN, &self.0);
+        // serializers other than this crate's don't take the value out
+        CUSTOM_VALUE.with(|slot| *slot.borrow_mut() = previous);
+        result
+    }
+}
+
+impl<'de, T> Deserialize<'de> for Custom<T>
+where
+    T: Deserialize<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        T::deserialize(deserializer).map(Custom)
+    }
+}
+
+/// Takes the copy of the value [`Custom::serialize`] passes on as [`TOKEN`] newtype.
+pub(crate) fn take() -> Option<Box<dyn Any>> {
+    CUSTOM_VALUE.with(|slot| slot.borrow_mut().take())
+}
+
+type CustomFn = Arc<dyn for<'lua> Fn(&dyn Any, &'lua Lua) -> Result<Value<'lua>> + Send + Sync>;
+
+/// Serializers for [`Custom`] values by the type they wrap, see
+/// [`SerializerOptions::register_custom`](crate::SerializerOptions::register_custom).
+#[derive(Clone, Default)]
+pub struct CustomSerializers {
+    by_type: HashMap<TypeId, CustomFn>,
+}
+
+impl CustomSerializers {
+    pub(crate) fn insert<T, F>(&mut self, f: F)
+    where
+        T: 'static,
+        F: for<'lua> Fn(&T, &'lua Lua) -> Result<Value<'lua>> + Send + Sync + 'static,
+    {
+        let f: CustomFn = Arc::new(move |value, lua| match value.downcast_ref::<T>() {
+            Some(value) => f(value, lua),
+            None => unreachable!("custom serializers are looked up by type"),
+        });
+        self.by_type.insert(TypeId::of::<T>(), f);
+    }
+
+    pub(crate) fn serialize<'lua>(
+        &self,
+        value: &dyn Any,
+        lua: &'lua Lua,
+    ) -> Option<Result<Value<'lua>>> {
+        let f = self.by_type.get(&value.type_id())?;
+        Some(f(value, lua))
+    }
+
+    pub(crate) fn contains(&self, value: &dyn Any) -> bool {
+        self.by_type.contains_key(&value.type_id())
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_type.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_type.is_empty()
+    }
+}
+
+impl fmt::Debug for CustomSerializers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CustomSerializers")
+            .field("len", &self.by_type.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Custom;
+    use crate::{from_value, to_value, to_value_with, SerializerOptions};
+    use mlua::{Lua, Table, Value};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+    struct Color {
+        r: u8,
+        g: u8,
+        b: u8,
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Theme {
+        background: Custom<Color>,
+        foreground: Color,
+        name: Custom<String>,
+    }
+
+    #[test]
+    fn registered_type() {
+        let lua = Lua::new();
+        let mut options = SerializerOptions::default();
+        options.register_custom(|c: &Color, _: &Lua| {
+            Ok(Value::Integer(
+                i64::from(c.r) << 16 | i64::from(c.g) << 8 | i64::from(c.b),
+            ))
+        });
+        assert_eq!(1, options.custom.len());
+
+        let color = Color { r: 1, g: 2, b: 3 };
+        let theme = Theme {
+            background: Custom(color),
+            foreground: color,
+            name: Custom("dark".to_string()),
+        };
+        let value = to_value_with(&lua, &theme, options).unwrap();
+        let table = match &value {
+            Value::Table(t) => t,
+            _ => panic!("expected table"),
+        };
+        assert_eq!(0x010203, table.get::<_, i64>("background").unwrap());
+        assert_eq!(
+            2,
+            table
+                .get::<_, Table>("foreground")
+                .unwrap()
+                .get::<_, u8>("g")
+                .unwrap()
+        );
+        assert_eq!("dark", table.get::<_, String>("name").unwrap());
+
+        // without the registration, the wrapped value is serialized as usual
+        let value = to_value(&lua, &theme).unwrap();
+        assert_eq!(theme, from_value::<Theme>(value).unwrap());
+        assert_eq!(
+            r#"{"background":{"r":1,"g":2,"b":3},"foreground":{"r":1,"g":2,"b":3},"name":"dark"}"#,
+            serde_json::to_string(&theme).unwrap()
+        );
+    }
+}
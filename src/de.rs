@@ -1,32 +1,418 @@
 // based on https://github.com/zrkn/rlua_serde/blob/master/src/de.rs
 
-use crate::error::{Error, Result};
-use mlua::{Table, TablePairs, TableSequence, Value};
+use crate::collect::ErrorCollector;
+use crate::error::{key_to_string, Error, Result};
+use crate::ser::EnumRepresentation;
+use mlua::{Function, Lua, MultiValue, RegistryKey, Table, TablePairs, Value};
 use serde::de::{
-    self, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess, SeqAccess, VariantAccess,
-    Visitor,
+    self, DeserializeOwned, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess, SeqAccess,
+    VariantAccess, Visitor,
 };
 use serde::Deserialize;
+use std::collections::{HashSet, VecDeque};
+use std::ops::Deref;
+use std::rc::Rc;
+
+/// Options to customize how Lua values are deserialized.
+#[derive(Clone, Debug)]
+pub struct DeserializerOptions<'lua> {
+    /// Parse map keys that are integer-looking strings (e.g. `"1"`) when deserializing them into
+    /// integer key types.
+    pub parse_stringy_int_keys: bool,
+    /// Include the keys present in a table in `missing field` errors.
+    pub verbose_missing_field: bool,
+    /// Values that are treated like `nil`, e.g. the `cjson.null` light userdata or the
+    /// `dkjson.null` table.
+    pub null_sentinels: Vec<Value<'lua>>,
+    /// Reject tables that mix array entries (integer keys) with named entries.
+    pub strict_table_shapes: bool,
+    /// How enum variants with data are represented.
+    pub enum_representation: EnumRepresentation,
+    /// Accept numeric strings (e.g. `"3.14"`) for float fields.
+    pub parse_string_floats: bool,
+    /// Match enum variant names case-insensitively (e.g. `"fast"` for a `Fast` variant).
+    pub case_insensitive_variants: bool,
+    /// Read sequences and tuple variants as 0-based arrays, i.e. starting at index `0` instead
+    /// of `1`.
+    pub zero_based_arrays: bool,
+    /// Consult a `__jsontype = "array" | "object"` metatable field (as set by some Lua JSON
+    /// libraries) to decide whether a table is a sequence or a map, e.g. for empty tables.
+    pub respect_jsontype_marker: bool,
+    /// Accept empty tables for unit (`()`) values, in addition to `nil`.
+    pub empty_table_as_unit: bool,
+    /// Accept the integers `1` and `0` for booleans, also as floats (`1.0` and `0.0`).
+    pub int_as_bool: bool,
+    /// Deserialize non-array tables into sequences of `(key, value)` pairs, e.g. into a
+    /// `Vec<(String, T)>`.
+    pub map_as_pairs: bool,
+    /// Accept integer-looking strings (e.g. `"42"`) for integer fields.
+    pub parse_string_ints: bool,
+    /// Accept floats without a fractional part (e.g. `3.0`) for integer fields.
+    pub integral_float_as_int: bool,
+    /// Accept numbers for string fields, formatted as decimal strings.
+    pub number_as_string: bool,
+    /// Accept integer enum discriminants, as an index into the enum's variants (starting at `0`),
+    /// both for the tag of internally tagged enums and for unit variants.
+    pub enum_discriminant_as_index: bool,
+    /// How to resolve strings given for numbers and numbers given for strings. Combines with
+    /// `parse_string_ints`, `parse_string_floats` and `number_as_string`.
+    pub number_string_policy: NumberStringPolicy,
+    /// Error if a key is visited twice while reading a struct or map, e.g. from a `__pairs`
+    /// metamethod that returns a key twice, or an integer key and an integer string key that
+    /// `parse_stringy_int_keys` reads as the same integer. This also covers the content serde
+    /// buffers for flattened fields. Otherwise the value visited last wins.
+    pub error_on_duplicate_field: bool,
+    /// Iterate maps via their `__pairs` metamethod, if present, e.g. for proxy tables that
+    /// compute their entries.
+    pub respect_pairs_metamethod: bool,
+    /// Accept `nil` (and the `null_sentinels`) for booleans, as `false`. Note that fields missing
+    /// from a table still need `#[serde(default)]`.
+    pub nil_as_false: bool,
+    /// Subtracted from integer map keys, the counterpart of
+    /// [`SerializerOptions::int_key_offset`](crate::SerializerOptions::int_key_offset).
+    pub int_key_offset: i64,
+    /// The value `is_human_readable` reports, `true` by default. Match
+    /// [`SerializerOptions::human_readable`](crate::SerializerOptions::human_readable).
+    pub human_readable: bool,
+    /// Error for strings longer than this many bytes when deserializing them into strings or
+    /// bytes, e.g. to limit the memory untrusted scripts can make the host allocate.
+    pub max_string_len: Option<usize>,
+    /// Accept `nil` (and the `null_sentinels`) for strings, as `""`, the counterpart of
+    /// [`SerializerOptions::empty_string_as_nil`](crate::SerializerOptions::empty_string_as_nil).
+    /// Note that fields missing from a table still need `#[serde(default)]`.
+    pub nil_as_empty_string: bool,
+    /// Trim leading and trailing whitespace from strings deserialized into strings. Map keys and
+    /// bytes are left as is.
+    pub trim_strings: bool,
+    /// Keep Lua integers and floats apart when the target type accepts any value, e.g. so that a
+    /// `serde_json::Value` gets integer numbers for integers, `true` by default. Set it to `false`
+    /// to visit all numbers as floats, for consumers that don't distinguish the two.
+    pub preserve_number_type: bool,
+    /// Follow up to this many `__index` tables (e.g. `leaf` -> `middle` -> `base` for `2`) when
+    /// deserializing structs, to also pick up inherited fields. Nearer tables shadow farther
+    /// ones. `0` disables it.
+    pub index_chain_depth: usize,
+}
+
+impl<'lua> Default for DeserializerOptions<'lua> {
+    fn default() -> Self {
+        DeserializerOptions {
+            parse_stringy_int_keys: false,
+            verbose_missing_field: false,
+            null_sentinels: Vec::new(),
+            strict_table_shapes: false,
+            enum_representation: Default::default(),
+            parse_string_floats: false,
+            case_insensitive_variants: false,
+            zero_based_arrays: false,
+            respect_jsontype_marker: false,
+            empty_table_as_unit: false,
+            int_as_bool: false,
+            map_as_pairs: false,
+            parse_string_ints: false,
+            integral_float_as_int: false,
+            number_as_string: false,
+            enum_discriminant_as_index: false,
+            number_string_policy: Default::default(),
+            error_on_duplicate_field: false,
+            respect_pairs_metamethod: false,
+            nil_as_false: false,
+            int_key_offset: 0,
+            human_readable: true,
+            max_string_len: None,
+            nil_as_empty_string: false,
+            trim_strings: false,
+            preserve_number_type: true,
+            index_chain_depth: 0,
+        }
+    }
+}
+
+impl<'lua> DeserializerOptions<'lua> {
+    /// Options that coerce between numbers, strings and booleans like a dynamic language would,
+    /// i.e. with all of `parse_string_ints`, `parse_string_floats`, `integral_float_as_int`,
+    /// `number_as_string`, `int_as_bool` and `parse_stringy_int_keys` enabled. Integers are
+    /// always accepted for floats, and single-character strings for chars.
+    pub fn dynamic_coercion() -> Self {
+        DeserializerOptions {
+            parse_string_ints: true,
+            parse_string_floats: true,
+            integral_float_as_int: true,
+            number_as_string: true,
+            int_as_bool: true,
+            parse_stringy_int_keys: true,
+            ..Default::default()
+        }
+    }
+
+    fn parse_string_ints(&self) -> bool {
+        self.parse_string_ints || self.number_string_policy == NumberStringPolicy::PreferNumber
+    }
+
+    fn parse_string_floats(&self) -> bool {
+        self.parse_string_floats || self.number_string_policy == NumberStringPolicy::PreferNumber
+    }
+
+    fn number_as_string(&self) -> bool {
+        self.number_as_string || self.number_string_policy == NumberStringPolicy::PreferString
+    }
+}
+
+/// How strings given for numbers and numbers given for strings are resolved, see
+/// [`DeserializerOptions::number_string_policy`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NumberStringPolicy {
+    /// Only accept values of the requested type.
+    #[default]
+    Strict,
+    /// Parse numeric strings (e.g. `"10"`) for integer and float fields.
+    PreferNumber,
+    /// Format numbers (e.g. `10`) as decimal strings for string fields.
+    PreferString,
+}
+
+/// The options of a deserialization together with state shared by all nested deserializers.
+/// Dereferences to the options.
+pub(crate) struct Context<'lua> {
+    options: DeserializerOptions<'lua>,
+    /// Set by [`from_value_collect_errors`](crate::from_value_collect_errors).
+    error_collector: Option<Rc<ErrorCollector>>,
+}
+
+impl<'lua> From<DeserializerOptions<'lua>> for Context<'lua> {
+    fn from(options: DeserializerOptions<'lua>) -> Self {
+        Context {
+            options,
+            error_collector: None,
+        }
+    }
+}
+
+impl<'lua> Deref for Context<'lua> {
+    type Target = DeserializerOptions<'lua>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.options
+    }
+}
 
 pub struct Deserializer<'lua> {
     value: Value<'lua>,
+    options: Rc<Context<'lua>>,
 }
 
-impl<'de> Deserializer<'de> {
-    pub fn from_value(value: Value<'de>) -> Self {
-        Deserializer { value }
+impl<'lua> Deserializer<'lua> {
+    pub fn from_value(value: Value<'lua>) -> Self {
+        Self::from_value_with(value, DeserializerOptions::default())
+    }
+
+    pub fn from_value_with(value: Value<'lua>, options: DeserializerOptions<'lua>) -> Self {
+        Deserializer {
+            value,
+            options: Rc::new(options.into()),
+        }
+    }
+
+    /// A deserializer that reports the path of failing values to `collector`.
+    pub(crate) fn with_error_collector(value: Value<'lua>, collector: Rc<ErrorCollector>) -> Self {
+        Deserializer {
+            value,
+            options: Rc::new(Context {
+                options: DeserializerOptions::default(),
+                error_collector: Some(collector),
+            }),
+        }
     }
 }
 
+/// Deserializes `value` into `T`.
+///
+/// Strings are copied out of Lua, so `T` can't borrow from `value`: `&str` fails to deserialize,
+/// and a `Cow<str>` is always `Cow::Owned`. Generic types only need the usual `T: Deserialize<'de>`
+/// bound.
 pub fn from_value<'a, T>(value: Value<'a>) -> Result<T>
 where
     T: Deserialize<'a>,
 {
-    let deserializer = Deserializer::from_value(value);
+    from_value_with(value, DeserializerOptions::default())
+}
+
+pub fn from_value_with<'a, T>(value: Value<'a>, options: DeserializerOptions<'a>) -> Result<T>
+where
+    T: Deserialize<'a>,
+{
+    let deserializer = Deserializer::from_value_with(value, options);
+    let t = T::deserialize(deserializer)?;
+    Ok(t)
+}
+
+/// Deserializes `value` into an existing `place` via `Deserialize::deserialize_in_place`, which
+/// lets e.g. a `Vec` reuse its allocation. Types without in-place support are simply replaced.
+pub fn from_value_into<'a, T>(value: Value<'a>, place: &mut T) -> Result<()>
+where
+    T: Deserialize<'a>,
+{
+    from_value_into_with(value, place, DeserializerOptions::default())
+}
+
+/// Like [`from_value_into`], but with `options`.
+pub fn from_value_into_with<'a, T>(
+    value: Value<'a>,
+    place: &mut T,
+    options: DeserializerOptions<'a>,
+) -> Result<()>
+where
+    T: Deserialize<'a>,
+{
+    let deserializer = Deserializer::from_value_with(value, options);
+    T::deserialize_in_place(deserializer, place)
+}
+
+/// Checks that `value` can be deserialized into `T`, without keeping the result. Useful to lint
+/// Lua configs against their Rust type. Errors are prefixed with the path of the failing value,
+/// e.g. `` `server.port`: ... ``.
+pub fn validate<T>(value: Value) -> Result<()>
+where
+    T: DeserializeOwned,
+{
+    crate::collect::from_value_with_path::<T>(value).map(drop)
+}
+
+/// Deserializes the value stored in the Lua registry under `key`.
+pub fn from_registry_value<'lua, T>(lua: &'lua Lua, key: &RegistryKey) -> Result<T>
+where
+    T: Deserialize<'lua>,
+{
+    let value = lua.registry_value::<Value>(key)?;
+    from_value(value)
+}
+
+/// Deserializes only the value found by following `path` through nested tables, e.g.
+/// `&["server", "tls"]` for `config.server.tls`.
+pub fn from_value_at<'a, T>(value: Value<'a>, path: &[&str]) -> Result<T>
+where
+    T: Deserialize<'a>,
+{
+    let mut value = value;
+    for (i, key) in path.iter().enumerate() {
+        let table = match value {
+            Value::Table(table) => table,
+            _ if i == 0 => return Err(serde::de::Error::custom("value is not a table")),
+            _ => {
+                return Err(serde::de::Error::custom(format!(
+                    "`{}` is not a table",
+                    path[..i].join(".")
+                )))
+            }
+        };
+        value = table.get(*key)?;
+        if value == Value::Nil {
+            return Err(serde::de::Error::custom(format!(
+                "missing key `{}`",
+                path[..=i].join(".")
+            )));
+        }
+    }
+    from_value(value)
+}
+
+/// Deserializes multiple Lua values (e.g. the results of `return a, b, c`) as a sequence.
+pub fn from_multi_value<'a, T>(values: MultiValue<'a>) -> Result<T>
+where
+    T: Deserialize<'a>,
+{
+    let deserializer = MultiValueDeserializer {
+        values: values.into_vec(),
+        options: Rc::new(DeserializerOptions::default().into()),
+    };
     let t = T::deserialize(deserializer)?;
     Ok(t)
 }
 
+impl<'lua> Deserializer<'lua> {
+    fn is_null(&self) -> bool {
+        self.value == Value::Nil || self.options.null_sentinels.contains(&self.value)
+    }
+}
+
+macro_rules! deserialize_coerced_int {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V>(self, visitor: V) -> Result<V::Value>
+            where
+                V: Visitor<'de>,
+            {
+                self.deserialize_int(visitor)
+            }
+        )*
+    };
+}
+
+impl<'lua> Deserializer<'lua> {
+    fn deserialize_int<'de, V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        match self.value {
+            Value::String(ref v) if self.options.parse_string_ints() => {
+                let s = v.to_str()?.trim();
+                if let Ok(v) = s.parse::<i64>() {
+                    return visitor.visit_i64(v);
+                }
+                if let Ok(v) = s.parse::<u64>() {
+                    return visitor.visit_u64(v);
+                }
+                Err(serde::de::Error::invalid_value(
+                    serde::de::Unexpected::Str(s),
+                    &"an integer",
+                ))
+            }
+            Value::Number(v)
+                if self.options.integral_float_as_int
+                    && v.fract() == 0.0
+                    && v >= i64::MIN as f64
+                    && v < i64::MAX as f64 =>
+            {
+                visitor.visit_i64(v as i64)
+            }
+            Value::Integer(v) => visitor.visit_i64(v),
+            _ => de::Deserializer::deserialize_any(self, visitor),
+        }
+    }
+
+    // `usize` and `isize` are deserialized as 64-bit integers, so check that the value fits on
+    // targets with a smaller pointer width.
+    fn deserialize_wide_int<'de, V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        if let Value::Integer(v) = self.value {
+            if usize::BITS < 64 {
+                check_pointer_width(v, &visitor, usize::BITS)?;
+            }
+        }
+        self.deserialize_int(visitor)
+    }
+}
+
+// Errors if `expected` is `usize` or `isize` and `v` doesn't fit into `bits` bits.
+fn check_pointer_width(v: i64, expected: &dyn de::Expected, bits: u32) -> Result<()> {
+    let expected = expected.to_string();
+    let (min, max) = match expected.as_str() {
+        "usize" => (0, (1i128 << bits) - 1),
+        "isize" => (-(1i128 << (bits - 1)), (1i128 << (bits - 1)) - 1),
+        _ => return Ok(()),
+    };
+    if (min..=max).contains(&(v as i128)) {
+        Ok(())
+    } else {
+        Err(serde::de::Error::custom(format!(
+            "value {} out of range for {} on this platform",
+            v, expected
+        )))
+    }
+}
+
 impl<'lua, 'de> de::Deserializer<'de> for Deserializer<'lua> {
     type Error = Error;
 
@@ -34,68 +420,251 @@ impl<'lua, 'de> de::Deserializer<'de> for Deserializer<'lua> {
     where
         V: Visitor<'de>,
     {
+        if self.is_null() {
+            return visitor.visit_unit();
+        }
+        check_string_len(&self.value, &self.options)?;
+
         match self.value {
             Value::Nil => visitor.visit_unit(),
             Value::Boolean(v) => visitor.visit_bool(v),
+            Value::Integer(v) if !self.options.preserve_number_type => visitor.visit_f64(v as f64),
             Value::Integer(v) => visitor.visit_i64(v),
             Value::Number(v) => visitor.visit_f64(v),
             Value::String(v) => visitor.visit_str(v.to_str()?),
             Value::Table(v) => {
+                check_table_shape(&v, &self.options)?;
                 // TODO: better way to distinguish between map and seq?
-                if is_seq(v.clone())? {
-                    let len = v.len()? as usize;
-                    let mut deserializer = SeqDeserializer(v.sequence_values());
-                    let seq = visitor.visit_seq(&mut deserializer)?;
-                    let remaining = deserializer.0.count();
-                    if remaining == 0 {
-                        Ok(seq)
-                    } else {
-                        Err(serde::de::Error::invalid_length(
-                            len,
-                            &"fewer elements in array",
-                        ))
-                    }
+                // Note: empty tables are visited as sequences. This matters for buffered content
+                // (internally tagged and untagged enums), where an empty table can therefore only
+                // be deserialized into sequence types, unless marked with a `__jsontype`.
+                if visit_as_seq(&v, &self.options)? {
+                    visit_seq_table(v, self.options, visitor)
                 } else {
-                    let len = v.len()? as usize;
-                    let mut deserializer = MapDeserializer(v.pairs(), None);
-                    let map = visitor.visit_map(&mut deserializer)?;
-                    let remaining = deserializer.0.count();
-                    if remaining == 0 {
-                        Ok(map)
-                    } else {
-                        Err(serde::de::Error::invalid_length(
-                            len,
-                            &"fewer elements in array",
-                        ))
-                    }
+                    MapDeserializer::visit(v, self.options, visitor)
                 }
             }
             _ => Err(serde::de::Error::custom("invalid value type")),
         }
     }
 
+    // Always visit tables as maps here, so that array-like tables (e.g. `{ [1] = "a" }`) can be
+    // deserialized into maps with integer keys.
+    fn deserialize_map<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        match self.value {
+            Value::Table(v) if !self.is_null() => {
+                check_table_shape(&v, &self.options)?;
+                MapDeserializer::visit(v, self.options, visitor)
+            }
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    deserialize_coerced_int! {
+        deserialize_i8 deserialize_i16 deserialize_i32
+        deserialize_u8 deserialize_u16 deserialize_u32
+    }
+
+    fn deserialize_i64<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_wide_int(visitor)
+    }
+
+    fn deserialize_u64<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_wide_int(visitor)
+    }
+
+    fn deserialize_str<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_string<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        check_string_len(&self.value, &self.options)?;
+        match self.value {
+            Value::Integer(v) if self.options.number_as_string() => {
+                visitor.visit_string(v.to_string())
+            }
+            Value::Number(v) if self.options.number_as_string() => {
+                visitor.visit_string(v.to_string())
+            }
+            Value::String(v) => match std::str::from_utf8(v.as_bytes()) {
+                Ok(s) if self.options.trim_strings => visitor.visit_str(s.trim()),
+                Ok(s) => visitor.visit_string(s.to_owned()),
+                Err(err) => Err(serde::de::Error::custom(format!(
+                    "invalid UTF-8 in string at byte offset {}",
+                    err.valid_up_to()
+                ))),
+            },
+            _ if self.options.nil_as_empty_string && self.is_null() => visitor.visit_str(""),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_bool<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        match self.value {
+            Value::Integer(_) | Value::Number(_) if self.options.int_as_bool => {
+                let (b, unexp) = match self.value {
+                    Value::Integer(v) => (v as f64, serde::de::Unexpected::Signed(v)),
+                    Value::Number(v) => (v, serde::de::Unexpected::Float(v)),
+                    _ => unreachable!(),
+                };
+                if b == 0.0 {
+                    visitor.visit_bool(false)
+                } else if b == 1.0 {
+                    visitor.visit_bool(true)
+                } else {
+                    Err(serde::de::Error::invalid_value(unexp, &"0 or 1"))
+                }
+            }
+            _ if self.options.nil_as_false && self.is_null() => visitor.visit_bool(false),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_struct<V>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        match self.value {
+            Value::Table(ref v)
+                if self.options.index_chain_depth > 0
+                    && !self.is_null()
+                    && index_table(v)?.is_some() =>
+            {
+                check_table_shape(v, &self.options)?;
+                let len = v.len()? as usize;
+                let pairs = MapPairs::with_index_chain(v.clone(), &self.options)?;
+                MapDeserializer::visit_pairs(pairs, len, self.options, visitor)
+            }
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_unit<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        match self.value {
+            Value::Table(ref v) if self.options.empty_table_as_unit => {
+                if is_empty(v) {
+                    visitor.visit_unit()
+                } else {
+                    Err(serde::de::Error::custom(
+                        "expected unit, found non-empty table",
+                    ))
+                }
+            }
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_unit_struct<V>(self, _name: &'static str, visitor: V) -> Result<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        self.deserialize_unit(visitor)
+    }
+
     #[inline]
     fn deserialize_option<V>(self, visitor: V) -> Result<V::Value>
     where
         V: serde::de::Visitor<'de>,
     {
-        match self.value {
-            Value::Nil => visitor.visit_none(),
-            _ => visitor.visit_some(self),
+        if self.is_null() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
         }
     }
 
     fn deserialize_enum<V>(
         self,
         _name: &str,
-        _variants: &'static [&'static str],
+        variants: &'static [&'static str],
         visitor: V,
     ) -> Result<V::Value>
     where
         V: serde::de::Visitor<'de>,
     {
-        let (variant, value) = match self.value {
-            Value::Table(value) => {
+        let (variant, value, tags) = match (&self.options.enum_representation, self.value) {
+            (EnumRepresentation::InternallyTagged { tag }, Value::Table(value)) => {
+                let variant = match value.raw_get::<_, Value>(tag.as_str())? {
+                    Value::String(variant) => utf8(&variant, "enum variant name")?.to_owned(),
+                    Value::Integer(index) if self.options.enum_discriminant_as_index => {
+                        variant_by_index(index, variants)?
+                    }
+                    Value::Nil => {
+                        return Err(serde::de::Error::custom(format!(
+                            "missing enum tag `{}`",
+                            tag
+                        )))
+                    }
+                    _ => {
+                        return Err(serde::de::Error::custom(format!(
+                            "enum tag `{}` is not a string",
+                            tag
+                        )))
+                    }
+                };
+                (variant, Some(Value::Table(value)), vec![tag.clone()])
+            }
+            (
+                EnumRepresentation::Tagged {
+                    name_key,
+                    index_key,
+                },
+                Value::Table(value),
+            ) => {
+                let variant = match value.raw_get::<_, Value>(name_key.as_str())? {
+                    Value::String(variant) => utf8(&variant, "enum variant name")?.to_owned(),
+                    Value::Nil => match value.raw_get::<_, Value>(index_key.as_str())? {
+                        Value::Integer(index) => variant_by_index(index, variants)?,
+                        Value::Nil => {
+                            return Err(serde::de::Error::custom(format!(
+                                "missing enum tag `{}` or `{}`",
+                                name_key, index_key
+                            )))
+                        }
+                        _ => {
+                            return Err(serde::de::Error::custom(format!(
+                                "enum tag `{}` is not an integer",
+                                index_key
+                            )))
+                        }
+                    },
+                    _ => {
+                        return Err(serde::de::Error::custom(format!(
+                            "enum tag `{}` is not a string",
+                            name_key
+                        )))
+                    }
+                };
+                let tags = vec![name_key.clone(), index_key.clone()];
+                (variant, Some(Value::Table(value)), tags)
+            }
+            (_, Value::Table(value)) => {
                 let mut iter = value.pairs::<String, Value>();
                 let (variant, value) = match iter.next() {
                     Some(v) => v?,
@@ -113,13 +682,30 @@ impl<'lua, 'de> de::Deserializer<'de> for Deserializer<'lua> {
                         &"map with a single key",
                     ));
                 }
-                (variant, Some(value))
+                (variant, Some(value), Vec::new())
+            }
+            (_, Value::String(variant)) => (
+                utf8(&variant, "enum variant name")?.to_owned(),
+                None,
+                Vec::new(),
+            ),
+            (_, Value::Integer(index)) if self.options.enum_discriminant_as_index => {
+                (variant_by_index(index, variants)?, None, Vec::new())
             }
-            Value::String(variant) => (variant.to_str()?.to_owned(), None),
             _ => return Err(serde::de::Error::custom("bad enum value")),
         };
+        let variant = if self.options.case_insensitive_variants {
+            resolve_variant_case_insensitive(variant, variants)?
+        } else {
+            variant
+        };
 
-        visitor.visit_enum(EnumDeserializer { variant, value })
+        visitor.visit_enum(EnumDeserializer {
+            variant,
+            value,
+            tags,
+            options: self.options,
+        })
     }
 
     fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value>
@@ -127,20 +713,28 @@ impl<'lua, 'de> de::Deserializer<'de> for Deserializer<'lua> {
         V: serde::de::Visitor<'de>,
     {
         match self.value {
-            Value::Table(v) => {
-                let len = v.len()? as usize;
-                let mut deserializer = SeqDeserializer(v.sequence_values());
+            Value::Table(v) if self.options.map_as_pairs && !is_seq(&v)? => {
+                check_table_shape(&v, &self.options)?;
+                let mut deserializer = PairsDeserializer {
+                    pairs: v.pairs(),
+                    consumed: 0,
+                    options: self.options,
+                };
                 let seq = visitor.visit_seq(&mut deserializer)?;
-                let remaining = deserializer.0.count();
+                let remaining = deserializer.pairs.count();
                 if remaining == 0 {
                     Ok(seq)
                 } else {
                     Err(serde::de::Error::invalid_length(
-                        len,
+                        deserializer.consumed + remaining,
                         &"fewer elements in array",
                     ))
                 }
             }
+            Value::Table(v) => {
+                check_table_shape(&v, &self.options)?;
+                visit_seq_table(v, self.options, visitor)
+            }
             _ => Err(serde::de::Error::custom("invalid value type")),
         }
     }
@@ -152,281 +746,3302 @@ impl<'lua, 'de> de::Deserializer<'de> for Deserializer<'lua> {
         self.deserialize_seq(visitor)
     }
 
+    // A tuple struct with a single field also accepts its field as is, like a newtype struct.
     fn deserialize_tuple_struct<V>(
         self,
         _name: &'static str,
-        _len: usize,
+        len: usize,
         visitor: V,
     ) -> Result<V::Value>
     where
         V: serde::de::Visitor<'de>,
     {
-        self.deserialize_seq(visitor)
+        match self.value {
+            Value::Table(_) => self.deserialize_seq(visitor),
+            value if len == 1 => {
+                let mut deserializer = SeqDeserializer {
+                    index: 0,
+                    len: 1,
+                    iter: std::iter::once(Ok(value)),
+                    options: self.options,
+                };
+                visitor.visit_seq(&mut deserializer)
+            }
+            _ => self.deserialize_seq(visitor),
+        }
     }
 
-    serde::forward_to_deserialize_any! {
-        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
-        byte_buf unit unit_struct newtype_struct
-        map struct identifier ignored_any
+    fn deserialize_f32<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        self.deserialize_f64(visitor)
     }
-}
-
-struct SeqDeserializer<'lua>(TableSequence<'lua, Value<'lua>>);
-
-impl<'lua, 'de> SeqAccess<'de> for SeqDeserializer<'lua> {
-    type Error = Error;
 
-    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+    fn deserialize_f64<V>(self, visitor: V) -> Result<V::Value>
     where
-        T: serde::de::DeserializeSeed<'de>,
+        V: serde::de::Visitor<'de>,
     {
-        match self.0.next() {
-            Some(value) => seed.deserialize(Deserializer { value: value? }).map(Some),
-            None => Ok(None),
+        match self.value {
+            Value::String(v) if self.options.parse_string_floats() => {
+                let s = v.to_str()?;
+                match s.trim().parse() {
+                    Ok(v) => visitor.visit_f64(v),
+                    Err(_) => Err(serde::de::Error::invalid_value(
+                        serde::de::Unexpected::Str(s),
+                        &visitor,
+                    )),
+                }
+            }
+            _ => self.deserialize_any(visitor),
         }
     }
 
-    fn size_hint(&self) -> Option<usize> {
-        match self.0.size_hint() {
-            (lower, Some(upper)) if lower == upper => Some(upper),
-            _ => None,
+    fn deserialize_i128<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        match self.value {
+            // 128-bit integers don't fit into Lua numbers and are stored as strings
+            Value::String(v) => {
+                let s = v.to_str()?;
+                match s.parse() {
+                    Ok(v) => visitor.visit_i128(v),
+                    Err(_) => Err(serde::de::Error::invalid_value(
+                        serde::de::Unexpected::Str(s),
+                        &visitor,
+                    )),
+                }
+            }
+            Value::Integer(v) => visitor.visit_i64(v),
+            _ => self.deserialize_any(visitor),
         }
     }
-}
-
-struct MapDeserializer<'lua>(
-    TablePairs<'lua, Value<'lua>, Value<'lua>>,
-    Option<Value<'lua>>,
-);
 
-impl<'lua, 'de> MapAccess<'de> for MapDeserializer<'lua> {
-    type Error = Error;
-
-    fn next_key_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+    fn deserialize_u128<V>(self, visitor: V) -> Result<V::Value>
     where
-        T: DeserializeSeed<'de>,
+        V: serde::de::Visitor<'de>,
     {
-        match self.0.next() {
-            Some(item) => {
-                let (key, value) = item?;
-                self.1 = Some(value);
-                let key_de = Deserializer { value: key };
-                seed.deserialize(key_de).map(Some)
+        match self.value {
+            Value::String(v) => {
+                let s = v.to_str()?;
+                match s.parse() {
+                    Ok(v) => visitor.visit_u128(v),
+                    Err(_) => Err(serde::de::Error::invalid_value(
+                        serde::de::Unexpected::Str(s),
+                        &visitor,
+                    )),
+                }
             }
-            None => Ok(None),
+            Value::Integer(v) => visitor.visit_i64(v),
+            _ => self.deserialize_any(visitor),
         }
     }
 
-    fn next_value_seed<T>(&mut self, seed: T) -> Result<T::Value>
+    fn deserialize_char<V>(self, visitor: V) -> Result<V::Value>
     where
-        T: DeserializeSeed<'de>,
+        V: serde::de::Visitor<'de>,
     {
-        match self.1.take() {
-            Some(value) => seed.deserialize(Deserializer { value }),
-            None => Err(serde::de::Error::custom("value is missing")),
+        match self.value {
+            Value::String(v) => {
+                let s = v.to_str()?;
+                let mut chars = s.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => visitor.visit_char(c),
+                    _ => Err(serde::de::Error::custom(format!(
+                        "expected a single character, found {:?}",
+                        s
+                    ))),
+                }
+            }
+            _ => self.deserialize_any(visitor),
         }
     }
 
-    fn size_hint(&self) -> Option<usize> {
-        match self.0.size_hint() {
-            (lower, Some(upper)) if lower == upper => Some(upper),
-            _ => None,
-        }
-    }
+    fn deserialize_bytes<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        self.deserialize_byte_buf(visitor)
+    }
+
+    // Lua strings are byte strings, so copy them in one go instead of going through
+    // `visit_str` (which also requires valid UTF-8). Arrays of integers (as written by the
+    // serializer) are collected into bytes first, so that visitors that only accept bytes work too.
+    fn deserialize_byte_buf<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        check_string_len(&self.value, &self.options)?;
+        match self.value {
+            Value::String(v) => visitor.visit_byte_buf(v.as_bytes().to_vec()),
+            Value::Table(_) if !self.is_null() => {
+                let bytes = Vec::<u8>::deserialize(self)?;
+                visitor.visit_byte_buf(bytes)
+            }
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_newtype_struct<V>(self, name: &'static str, visitor: V) -> Result<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        // name the newtype in errors, e.g. for a `Port(u16)` that is out of range, but keep
+        // structured errors as they are
+        visitor.visit_newtype_struct(self).map_err(|err| match err {
+            Error::Message(msg) => {
+                Error::Message(format!("failed to deserialize {}: {}", name, msg))
+            }
+            err => err,
+        })
+    }
+
+    fn deserialize_identifier<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        self.deserialize_any(visitor)
+    }
+
+    fn deserialize_ignored_any<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        visitor.visit_unit()
+    }
+
+    fn is_human_readable(&self) -> bool {
+        self.options.human_readable
+    }
 }
 
-struct EnumDeserializer<'lua> {
-    variant: String,
-    value: Option<Value<'lua>>,
+struct MultiValueDeserializer<'lua> {
+    values: Vec<Value<'lua>>,
+    options: Rc<Context<'lua>>,
+}
+
+impl<'lua, 'de> de::Deserializer<'de> for MultiValueDeserializer<'lua> {
+    type Error = Error;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let len = self.values.len();
+        let mut deserializer = SeqDeserializer {
+            index: 0,
+            len,
+            iter: self.values.into_iter().map(Ok),
+            options: self.options,
+        };
+        let seq = visitor.visit_seq(&mut deserializer)?;
+        let remaining = deserializer.iter.count();
+        if remaining == 0 {
+            Ok(seq)
+        } else {
+            Err(serde::de::Error::invalid_length(
+                len,
+                &"fewer elements in array",
+            ))
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
+        byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+
+    fn is_human_readable(&self) -> bool {
+        self.options.human_readable
+    }
+}
+
+/// Visits the entries of a table as a sequence of `(key, value)` pairs.
+struct PairsDeserializer<'lua> {
+    pairs: TablePairs<'lua, Value<'lua>, Value<'lua>>,
+    /// The number of pairs visited so far.
+    consumed: usize,
+    options: Rc<Context<'lua>>,
+}
+
+impl<'lua, 'de> SeqAccess<'de> for PairsDeserializer<'lua> {
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+    where
+        T: serde::de::DeserializeSeed<'de>,
+    {
+        match self.pairs.next() {
+            Some(pair) => {
+                let (key, value) = pair?;
+                self.consumed += 1;
+                seed.deserialize(MultiValueDeserializer {
+                    values: vec![key, value],
+                    options: self.options.clone(),
+                })
+                .map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// The largest size hint reported for a sequence. The length of a table can be far larger than
+/// its number of elements if it has holes, and visitors pre-allocate by the hint.
+const MAX_SIZE_HINT: usize = 1 << 16;
+
+struct SeqDeserializer<'lua, I> {
+    iter: I,
+    /// The number of elements visited so far.
+    index: usize,
+    /// The number of elements, e.g. the length of the table.
+    len: usize,
+    options: Rc<Context<'lua>>,
+}
+
+impl<'lua, 'de, I> SeqAccess<'de> for SeqDeserializer<'lua, I>
+where
+    I: Iterator<Item = mlua::Result<Value<'lua>>>,
+{
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+    where
+        T: serde::de::DeserializeSeed<'de>,
+    {
+        let value = match self.iter.next() {
+            Some(value) => value?,
+            None => return Ok(None),
+        };
+        self.index += 1;
+        let deserializer = Deserializer {
+            value,
+            options: self.options.clone(),
+        };
+        match &self.options.error_collector {
+            Some(collector) => collector
+                .deserialize(self.index.to_string(), seed, deserializer)
+                .map(Some),
+            None => seed.deserialize(deserializer).map(Some),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len.saturating_sub(self.index).min(MAX_SIZE_HINT))
+    }
+}
+
+struct MapDeserializer<'lua> {
+    pairs: MapPairs<'lua>,
+    /// The entries whose key `next_key_seed` returned, but whose value wasn't taken by
+    /// `next_value_seed` yet, oldest first. The keys are kept to name them in errors.
+    pending: VecDeque<(Value<'lua>, Value<'lua>)>,
+    /// Keys to skip, e.g. the tag of an internally tagged enum.
+    skip_keys: Vec<String>,
+    /// The keys seen so far, collected if `verbose_missing_field` is set.
+    keys: Option<Vec<String>>,
+    /// The keys seen so far, collected if `error_on_duplicate_field` is set.
+    seen: Option<HashSet<SeenKey>>,
+    options: Rc<Context<'lua>>,
+}
+
+/// The entries of a table, either iterated directly or collected, e.g. from its `__pairs`
+/// metamethod.
+enum MapPairs<'lua> {
+    Raw(TablePairs<'lua, Value<'lua>, Value<'lua>>),
+    Collected(std::vec::IntoIter<(Value<'lua>, Value<'lua>)>),
+}
+
+impl<'lua> MapPairs<'lua> {
+    fn new(table: Table<'lua>, options: &DeserializerOptions<'lua>) -> Result<Self> {
+        let pairs = match table.get_metatable() {
+            Some(metatable) if options.respect_pairs_metamethod => {
+                metatable.raw_get::<_, Value>("__pairs")?
+            }
+            _ => Value::Nil,
+        };
+        let pairs = match pairs {
+            Value::Function(pairs) => pairs,
+            _ => return Ok(MapPairs::Raw(table.pairs())),
+        };
+
+        let (next, state, mut key): (Function, Value, Value) = pairs.call(table)?;
+        let mut entries = Vec::new();
+        loop {
+            let (k, v): (Value, Value) = next.call((state.clone(), key))?;
+            if k == Value::Nil {
+                break;
+            }
+            entries.push((k.clone(), v));
+            key = k;
+        }
+        Ok(MapPairs::Collected(entries.into_iter()))
+    }
+
+    /// The entries of `table` followed by those of its `__index` tables that it doesn't shadow,
+    /// see `index_chain_depth`.
+    fn with_index_chain(table: Table<'lua>, options: &DeserializerOptions<'lua>) -> Result<Self> {
+        let mut entries: Vec<(Value, Value)> = Vec::new();
+        let mut seen = HashSet::new();
+        let mut table = Some(table);
+        for _ in 0..=options.index_chain_depth {
+            let level = match table.take() {
+                Some(level) => level,
+                None => break,
+            };
+            table = index_table(&level)?;
+            for pair in MapPairs::new(level, options)? {
+                let (key, value) = pair?;
+                let shadowed = match SeenKey::new(&key, options) {
+                    Some(seen_key) => !seen.insert(seen_key),
+                    // reference keys are rare, compare them one by one
+                    None => entries.iter().any(|(k, _)| *k == key),
+                };
+                if !shadowed {
+                    entries.push((key, value));
+                }
+            }
+        }
+        Ok(MapPairs::Collected(entries.into_iter()))
+    }
+}
+
+impl<'lua> Iterator for MapPairs<'lua> {
+    type Item = mlua::Result<(Value<'lua>, Value<'lua>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            MapPairs::Raw(pairs) => pairs.next(),
+            MapPairs::Collected(entries) => entries.next().map(Ok),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self {
+            MapPairs::Raw(pairs) => pairs.size_hint(),
+            MapPairs::Collected(entries) => entries.size_hint(),
+        }
+    }
+}
+
+impl<'lua> MapDeserializer<'lua> {
+    fn visit<'de, V>(table: Table<'lua>, options: Rc<Context<'lua>>, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let len = table.len()? as usize;
+        let pairs = MapPairs::new(table, &options)?;
+        Self::visit_pairs(pairs, len, options, visitor)
+    }
+
+    fn visit_pairs<'de, V>(
+        pairs: MapPairs<'lua>,
+        len: usize,
+        options: Rc<Context<'lua>>,
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let mut deserializer = MapDeserializer {
+            pairs,
+            pending: VecDeque::new(),
+            skip_keys: Vec::new(),
+            keys: if options.verbose_missing_field {
+                Some(Vec::new())
+            } else {
+                None
+            },
+            seen: new_seen(&options),
+            options,
+        };
+        let map = visitor
+            .visit_map(&mut deserializer)
+            .map_err(|err| deserializer.with_present_keys(err))?;
+        let remaining = deserializer.pairs.count();
+        if remaining == 0 {
+            Ok(map)
+        } else {
+            Err(serde::de::Error::invalid_length(
+                len,
+                &"fewer elements in array",
+            ))
+        }
+    }
+
+    fn with_present_keys(&mut self, err: Error) -> Error {
+        match (err, self.keys.take()) {
+            (Error::MissingField(field), Some(mut keys)) => {
+                keys.sort();
+                Error::Message(format!(
+                    "missing field `{}`; present keys: {}",
+                    field,
+                    keys.join(", ")
+                ))
+            }
+            (err, _) => err,
+        }
+    }
+}
+
+impl<'lua, 'de> MapAccess<'de> for MapDeserializer<'lua> {
+    type Error = Error;
+
+    fn next_key_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        match self.pairs.next() {
+            Some(item) => {
+                let (key, value) = item?;
+                if is_skipped(&key, &self.skip_keys) {
+                    return self.next_key_seed(seed);
+                }
+                if let Some(keys) = &mut self.keys {
+                    keys.push(key_to_string(&key));
+                }
+                self.pending.push_back((key.clone(), value));
+                let key = match key {
+                    Value::Integer(i) if self.options.int_key_offset != 0 => Value::Integer(
+                        i.checked_sub(self.options.int_key_offset).ok_or_else(|| {
+                            Error::Message(format!(
+                                "integer map key {} overflows with int_key_offset {}",
+                                i, self.options.int_key_offset
+                            ))
+                        })?,
+                    ),
+                    key => key,
+                };
+                let seen_key = SeenKey::new(&key, &self.options);
+                if let (Some(seen), Some(seen_key)) = (&mut self.seen, seen_key) {
+                    if !seen.insert(seen_key) {
+                        let (key, _) = self.pending.back().expect("entry was just pushed");
+                        return Err(serde::de::Error::custom(format!(
+                            "duplicate field `{}`",
+                            key_to_string(key)
+                        )));
+                    }
+                }
+                let key_de = MapKeyDeserializer {
+                    value: key,
+                    options: self.options.clone(),
+                };
+                seed.deserialize(key_de).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<T>(&mut self, seed: T) -> Result<T::Value>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        let (key, value) = match self.pending.pop_front() {
+            Some(entry) => entry,
+            None => {
+                return Err(serde::de::Error::custom(
+                    "value is missing, `next_value` must follow a call to `next_key`",
+                ))
+            }
+        };
+        let deserializer = Deserializer {
+            value,
+            options: self.options.clone(),
+        };
+        match &self.options.error_collector {
+            Some(collector) => collector.deserialize(key_to_string(&key), seed, deserializer),
+            None => seed.deserialize(deserializer).map_err(|err| {
+                if err.is_non_zero() {
+                    err.prepend(key_to_string(&key))
+                } else {
+                    err
+                }
+            }),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        match self.pairs.size_hint() {
+            (lower, Some(upper)) if lower == upper => Some(upper),
+            _ => None,
+        }
+    }
+}
+
+struct MapKeyDeserializer<'lua> {
+    value: Value<'lua>,
+    options: Rc<Context<'lua>>,
+}
+
+impl<'lua> MapKeyDeserializer<'lua> {
+    fn into_deserializer(self) -> Deserializer<'lua> {
+        Deserializer {
+            value: self.value,
+            options: self.options,
+        }
+    }
+
+    fn stringy_int(&self) -> Result<Option<&str>> {
+        match &self.value {
+            Value::String(s) if self.options.parse_stringy_int_keys => Ok(Some(s.to_str()?)),
+            _ => Ok(None),
+        }
+    }
+}
+
+macro_rules! deserialize_stringy_int_key {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V>(self, visitor: V) -> Result<V::Value>
+            where
+                V: Visitor<'de>,
+            {
+                if let Some(s) = self.stringy_int()? {
+                    if let Ok(v) = s.parse::<i64>() {
+                        return visitor.visit_i64(v);
+                    }
+                    if let Ok(v) = s.parse::<u64>() {
+                        return visitor.visit_u64(v);
+                    }
+                }
+                // a float key like `1.0` is the same table key as `1`
+                if let Value::Number(n) = self.value {
+                    if n.fract() == 0.0 && n >= i64::MIN as f64 && n < i64::MAX as f64 {
+                        return visitor.visit_i64(n as i64);
+                    }
+                }
+                de::Deserializer::$method(self.into_deserializer(), visitor)
+            }
+        )*
+    };
 }
 
-impl<'lua, 'de> EnumAccess<'de> for EnumDeserializer<'lua> {
-    type Error = Error;
-    type Variant = VariantDeserializer<'lua>;
+macro_rules! forward_to_deserializer {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V>(self, visitor: V) -> Result<V::Value>
+            where
+                V: Visitor<'de>,
+            {
+                de::Deserializer::$method(self.into_deserializer(), visitor)
+            }
+        )*
+    };
+}
+
+impl<'lua, 'de> de::Deserializer<'de> for MapKeyDeserializer<'lua> {
+    type Error = Error;
+
+    deserialize_stringy_int_key! {
+        deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64
+    }
+
+    forward_to_deserializer! {
+        deserialize_any deserialize_bool deserialize_i128 deserialize_u128
+        deserialize_f32 deserialize_f64 deserialize_char
+        deserialize_bytes deserialize_byte_buf
+        deserialize_option deserialize_unit deserialize_seq deserialize_map
+        deserialize_ignored_any
+    }
+
+    fn deserialize_str<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        check_string_len(&self.value, &self.options)?;
+        match &self.value {
+            Value::String(s) => visitor.visit_str(utf8(s, "map key")?),
+            _ => de::Deserializer::deserialize_str(self.into_deserializer(), visitor),
+        }
+    }
+
+    fn deserialize_string<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_identifier<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        check_string_len(&self.value, &self.options)?;
+        match &self.value {
+            Value::String(s) => visitor.visit_str(utf8(s, "struct field key")?),
+            _ => de::Deserializer::deserialize_identifier(self.into_deserializer(), visitor),
+        }
+    }
+
+    fn deserialize_unit_struct<V>(self, name: &'static str, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        de::Deserializer::deserialize_unit_struct(self.into_deserializer(), name, visitor)
+    }
+
+    fn deserialize_newtype_struct<V>(self, name: &'static str, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        de::Deserializer::deserialize_newtype_struct(self.into_deserializer(), name, visitor)
+    }
+
+    fn deserialize_tuple<V>(self, len: usize, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        de::Deserializer::deserialize_tuple(self.into_deserializer(), len, visitor)
+    }
+
+    fn deserialize_tuple_struct<V>(
+        self,
+        name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        de::Deserializer::deserialize_tuple_struct(self.into_deserializer(), name, len, visitor)
+    }
+
+    fn deserialize_struct<V>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        de::Deserializer::deserialize_struct(self.into_deserializer(), name, fields, visitor)
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        de::Deserializer::deserialize_enum(self.into_deserializer(), name, variants, visitor)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        self.options.human_readable
+    }
+}
+
+struct EnumDeserializer<'lua> {
+    variant: String,
+    value: Option<Value<'lua>>,
+    /// The tag keys if the variant is (internally) tagged, which are excluded from the content.
+    tags: Vec<String>,
+    options: Rc<Context<'lua>>,
+}
+
+impl<'lua, 'de> EnumAccess<'de> for EnumDeserializer<'lua> {
+    type Error = Error;
+    type Variant = VariantDeserializer<'lua>;
+
+    fn variant_seed<T>(self, seed: T) -> Result<(T::Value, Self::Variant)>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        let variant = self.variant.into_deserializer();
+        let variant_access = VariantDeserializer {
+            value: self.value,
+            tags: self.tags,
+            options: self.options,
+        };
+        seed.deserialize(variant).map(|v| (v, variant_access))
+    }
+}
+
+struct VariantDeserializer<'lua> {
+    value: Option<Value<'lua>>,
+    tags: Vec<String>,
+    options: Rc<Context<'lua>>,
+}
+
+impl<'lua, 'de> VariantAccess<'de> for VariantDeserializer<'lua> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        match self.value {
+            Some(_) if !self.tags.is_empty() => Ok(()),
+            Some(_) => Err(serde::de::Error::invalid_type(
+                serde::de::Unexpected::NewtypeVariant,
+                &"unit variant",
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        match self.value {
+            Some(Value::Table(table)) if !self.tags.is_empty() => {
+                seed.deserialize(TaggedContentDeserializer {
+                    table,
+                    tags: self.tags,
+                    options: self.options,
+                })
+            }
+            Some(value) => seed.deserialize(Deserializer {
+                value,
+                options: self.options,
+            }),
+            None => Err(serde::de::Error::invalid_type(
+                serde::de::Unexpected::UnitVariant,
+                &"newtype variant",
+            )),
+        }
+    }
+
+    fn tuple_variant<V>(self, _len: usize, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        match self.value {
+            Some(Value::Table(v)) if !self.tags.is_empty() || !is_seq(&v)? => {
+                let first = if self.options.zero_based_arrays { 0 } else { 1 };
+                let values = positional_values(v, &self.tags, first)?;
+                let len = values.len();
+                let mut deserializer = SeqDeserializer {
+                    index: 0,
+                    len,
+                    iter: values.into_iter().map(Ok),
+                    options: self.options,
+                };
+                let seq = visitor.visit_seq(&mut deserializer)?;
+                let remaining = deserializer.iter.count();
+                if remaining == 0 {
+                    Ok(seq)
+                } else {
+                    Err(serde::de::Error::invalid_length(
+                        len,
+                        &"fewer elements in array",
+                    ))
+                }
+            }
+            Some(value) => serde::Deserializer::deserialize_seq(
+                Deserializer {
+                    value,
+                    options: self.options,
+                },
+                visitor,
+            ),
+            None => Err(serde::de::Error::invalid_type(
+                serde::de::Unexpected::UnitVariant,
+                &"tuple variant",
+            )),
+        }
+    }
+
+    fn struct_variant<V>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        match self.value {
+            Some(Value::Table(table)) if !self.tags.is_empty() => {
+                serde::Deserializer::deserialize_map(
+                    TaggedContentDeserializer {
+                        table,
+                        tags: self.tags,
+                        options: self.options,
+                    },
+                    visitor,
+                )
+            }
+            Some(value) => serde::Deserializer::deserialize_map(
+                Deserializer {
+                    value,
+                    options: self.options,
+                },
+                visitor,
+            ),
+            None => Err(serde::de::Error::invalid_type(
+                serde::de::Unexpected::UnitVariant,
+                &"struct variant",
+            )),
+        }
+    }
+}
+
+/// Like `to_str`, but names `what` wasn't valid UTF-8 in the error.
+fn utf8<'a>(s: &'a mlua::String, what: &str) -> Result<&'a str> {
+    s.to_str().map_err(|_| {
+        Error::Message(format!(
+            "{} is not valid UTF-8: {:?}",
+            what,
+            String::from_utf8_lossy(s.as_bytes())
+        ))
+    })
+}
+
+/// The content of a tagged variant, i.e. its table without the tag keys.
+struct TaggedContentDeserializer<'lua> {
+    table: Table<'lua>,
+    tags: Vec<String>,
+    options: Rc<Context<'lua>>,
+}
+
+impl<'lua, 'de> de::Deserializer<'de> for TaggedContentDeserializer<'lua> {
+    type Error = Error;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_map<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let seen = new_seen(&self.options);
+        let mut deserializer = MapDeserializer {
+            pairs: MapPairs::new(self.table, &self.options)?,
+            pending: VecDeque::new(),
+            skip_keys: self.tags,
+            keys: None,
+            seen,
+            options: self.options,
+        };
+        visitor.visit_map(&mut deserializer)
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V>(self, _name: &'static str, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        unit unit_struct seq tuple tuple_struct struct enum identifier ignored_any
+    }
+
+    fn is_human_readable(&self) -> bool {
+        self.options.human_readable
+    }
+}
+
+fn check_string_len(value: &Value, options: &DeserializerOptions) -> Result<()> {
+    match (value, options.max_string_len) {
+        (Value::String(s), Some(max)) if s.as_bytes().len() > max => Err(Error::Message(format!(
+            "string of {} bytes exceeds max_string_len of {}",
+            s.as_bytes().len(),
+            max
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn is_skipped(key: &Value, skip_keys: &[String]) -> bool {
+    match key {
+        Value::String(s) => skip_keys.iter().any(|k| s.as_bytes() == k.as_bytes()),
+        _ => false,
+    }
+}
+
+/// Collects the values of a table whose keys are the positions `first..`, given either as
+/// integers or as integer strings (e.g. `{ ["1"] = a, ["2"] = b }`), ordered by position. The
+/// `skip_keys` (e.g. the tag of an internally tagged enum) are ignored.
+fn positional_values<'lua>(
+    val: Table<'lua>,
+    skip_keys: &[String],
+    first: i64,
+) -> Result<Vec<Value<'lua>>> {
+    let mut values = Vec::new();
+    for pair in val.pairs::<Value, Value>() {
+        let (key, value) = pair?;
+        if is_skipped(&key, skip_keys) {
+            continue;
+        }
+        let index = match &key {
+            Value::Integer(i) => Some(*i),
+            Value::String(s) => s.to_str().ok().and_then(|s| s.parse().ok()),
+            _ => None,
+        };
+        match index {
+            Some(index) => values.push((index, value)),
+            None => {
+                return Err(serde::de::Error::custom(format!(
+                    "expected tuple variant as an array or a table with positional keys, found key `{}`",
+                    key_to_string(&key)
+                )))
+            }
+        }
+    }
+
+    values.sort_by_key(|(index, _)| *index);
+    for (expected, (index, _)) in (first..).zip(&values) {
+        if *index != expected {
+            return Err(serde::de::Error::custom(format!(
+                "expected tuple variant positions to be contiguous from {}, missing position {}",
+                first, expected
+            )));
+        }
+    }
+
+    Ok(values.into_iter().map(|(_, value)| value).collect())
+}
+
+fn variant_by_index(index: i64, variants: &'static [&'static str]) -> Result<String> {
+    if index >= 0 && (index as usize) < variants.len() {
+        Ok(variants[index as usize].to_string())
+    } else {
+        Err(serde::de::Error::custom(format!(
+            "enum discriminant {} out of range, expected 0..{}",
+            index,
+            variants.len()
+        )))
+    }
+}
+
+/// Maps `variant` onto the variant of `variants` that equals it case-insensitively. Exact matches
+/// win; unknown names are passed through so that serde reports them as unknown variants.
+fn resolve_variant_case_insensitive(
+    variant: String,
+    variants: &'static [&'static str],
+) -> Result<String> {
+    if variants.contains(&variant.as_str()) {
+        return Ok(variant);
+    }
+
+    let folded = variant.to_lowercase();
+    let mut candidates = variants.iter().filter(|v| v.to_lowercase() == folded);
+    match (candidates.next(), candidates.next()) {
+        (Some(v), None) => Ok((*v).to_string()),
+        (Some(a), Some(b)) => Err(serde::de::Error::custom(format!(
+            "ambiguous variant `{}`, matches both `{}` and `{}`",
+            variant, a, b
+        ))),
+        _ => Ok(variant),
+    }
+}
+
+/// Errors if `strict_table_shapes` is set and the table mixes array entries and named entries.
+fn check_table_shape(table: &Table, options: &DeserializerOptions) -> Result<()> {
+    if !options.strict_table_shapes {
+        return Ok(());
+    }
+
+    let (mut positional, mut named) = (false, false);
+    for pair in table.clone().pairs::<Value, Value>() {
+        match pair?.0 {
+            Value::Integer(_) => positional = true,
+            _ => named = true,
+        }
+        if positional && named {
+            return Err(serde::de::Error::custom(
+                "table mixes array entries and named entries",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The table set as `__index` of the table's metatable, if any.
+fn index_table<'lua>(table: &Table<'lua>) -> Result<Option<Table<'lua>>> {
+    match table.get_metatable() {
+        Some(metatable) => match metatable.raw_get::<_, Value>("__index")? {
+            Value::Table(index) => Ok(Some(index)),
+            _ => Ok(None),
+        },
+        None => Ok(None),
+    }
+}
+
+// Whether the table's metatable marks it as an array (`Some(true)`) or an object
+// (`Some(false)`).
+fn jsontype_marker(table: &Table, options: &DeserializerOptions) -> Result<Option<bool>> {
+    if !options.respect_jsontype_marker {
+        return Ok(None);
+    }
+    let metatable = match table.get_metatable() {
+        Some(metatable) => metatable,
+        None => return Ok(None),
+    };
+    Ok(match metatable.raw_get::<_, Value>("__jsontype")? {
+        Value::String(s) if s == "array" => Some(true),
+        Value::String(s) if s == "object" => Some(false),
+        _ => None,
+    })
+}
+
+/// A map key as Lua compares keys, to detect a key visited twice, e.g. by a `__pairs`
+/// metamethod. Integer strings count as integers with `parse_stringy_int_keys`. Tables and other
+/// reference types are left out.
+#[derive(PartialEq, Eq, Hash)]
+enum SeenKey {
+    Bool(bool),
+    Int(i64),
+    Float(u64),
+    Str(Vec<u8>),
+}
+
+impl SeenKey {
+    fn new(key: &Value, options: &DeserializerOptions) -> Option<Self> {
+        match key {
+            Value::Boolean(b) => Some(SeenKey::Bool(*b)),
+            Value::Integer(i) => Some(SeenKey::Int(*i)),
+            // Lua stores float keys with an integral value as integers
+            Value::Number(n)
+                if n.fract() == 0.0 && *n >= i64::MIN as f64 && *n < i64::MAX as f64 =>
+            {
+                Some(SeenKey::Int(*n as i64))
+            }
+            Value::Number(n) => Some(SeenKey::Float(n.to_bits())),
+            Value::String(s) if options.parse_stringy_int_keys => {
+                match s.to_str().ok().and_then(|s| s.parse().ok()) {
+                    Some(i) => Some(SeenKey::Int(i)),
+                    None => Some(SeenKey::Str(s.as_bytes().to_vec())),
+                }
+            }
+            Value::String(s) => Some(SeenKey::Str(s.as_bytes().to_vec())),
+            _ => None,
+        }
+    }
+}
+
+/// Whether `deserialize_any` visits `table` as a sequence rather than a map.
+fn visit_as_seq(table: &Table, options: &DeserializerOptions) -> Result<bool> {
+    match jsontype_marker(table, options)? {
+        Some(seq) => Ok(seq),
+        None if options.zero_based_arrays => Ok(is_seq(table)? || is_zero_based_seq(table)?),
+        None => is_seq(table),
+    }
+}
+
+/// Whether the table's keys are exactly `0` to `#table`.
+fn is_zero_based_seq(table: &Table) -> Result<bool> {
+    let len = table.len()?;
+    let mut count = 0;
+    for pair in table.clone().pairs::<Value, Value>() {
+        match pair?.0 {
+            Value::Integer(i) if (0..=len).contains(&i) => count += 1,
+            _ => return Ok(false),
+        }
+    }
+    Ok(count == len + 1)
+}
+
+/// Visits the sequence part of a table, starting at index `0` with `zero_based_arrays`.
+fn visit_seq_table<'lua, 'de, V>(
+    table: Table<'lua>,
+    options: Rc<Context<'lua>>,
+    visitor: V,
+) -> Result<V::Value>
+where
+    V: serde::de::Visitor<'de>,
+{
+    let first = if options.zero_based_arrays {
+        Some(table.get::<_, Value>(0)?).filter(|v| *v != Value::Nil)
+    } else {
+        None
+    };
+    let len = table.len()? as usize + first.is_some() as usize;
+    let mut deserializer = SeqDeserializer {
+        index: 0,
+        len,
+        iter: first.into_iter().map(Ok).chain(table.sequence_values()),
+        options,
+    };
+    let seq = visitor.visit_seq(&mut deserializer)?;
+    let remaining = deserializer.iter.count();
+    if remaining == 0 {
+        Ok(seq)
+    } else {
+        Err(serde::de::Error::invalid_length(
+            len,
+            &"fewer elements in array",
+        ))
+    }
+}
+
+fn new_seen(options: &DeserializerOptions) -> Option<HashSet<SeenKey>> {
+    if options.error_on_duplicate_field {
+        Some(HashSet::new())
+    } else {
+        None
+    }
+}
+
+fn is_empty(table: &Table) -> bool {
+    table.clone().pairs::<Value, Value>().next().is_none()
+}
+
+/// Whether a table is deserialized as a sequence or as a map, see [`classify_table`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TableKind {
+    Seq,
+    Map,
+}
+
+/// Classifies `table` the way the [`Deserializer`] does when the target type accepts both
+/// sequences and maps (e.g. `serde_json::Value` or untagged enums): tables whose keys are exactly
+/// `1..=n` are sequences, all others are maps. Empty tables are sequences.
+///
+/// ```
+/// use mlua::{Lua, Table};
+/// use serde_mlua::{classify_table, TableKind};
+///
+/// let lua = Lua::new();
+/// let list: Table = lua.load("return { 'a', 'b' }").eval().unwrap();
+/// assert_eq!(TableKind::Seq, classify_table(&list).unwrap());
+/// let sparse: Table = lua.load("return { [1] = 'a', [3] = 'c' }").eval().unwrap();
+/// assert_eq!(TableKind::Map, classify_table(&sparse).unwrap());
+/// ```
+#[allow(clippy::explicit_counter_loop)]
+pub fn classify_table(table: &Table) -> Result<TableKind> {
+    let mut next_key = 1;
+    for pair in table.clone().pairs::<Value, Value>() {
+        let (key, _) = pair?;
+        if key != Value::Integer(next_key) {
+            return Ok(TableKind::Map);
+        }
+        next_key += 1;
+    }
+
+    Ok(TableKind::Seq)
+}
+
+fn is_seq(table: &Table) -> Result<bool> {
+    Ok(classify_table(table)? == TableKind::Seq)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        classify_table, from_multi_value, from_registry_value, from_value, from_value_at,
+        from_value_into, from_value_into_with, from_value_with, validate, DeserializerOptions,
+        MapKeyDeserializer, TableKind,
+    };
+    use crate::ser::EnumRepresentation;
+    use mlua::{Lua, MultiValue, Value};
+    use ordered_float::OrderedFloat;
+    use serde::Deserialize;
+    use std::borrow::Cow;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use std::sync::Arc;
+
+    #[test]
+    fn enum_variant_with_empty_seq() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        #[serde(tag = "id")]
+        enum Variant {
+            Combo { params: ComboParams },
+        }
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct ComboParams {
+            values: Vec<i64>,
+        }
+
+        let expected = Variant::Combo {
+            params: ComboParams { values: vec![1] },
+        };
+
+        let lua = Lua::new();
+        let result = lua
+            .load(
+                r#"
+                return {
+                    id = "Combo",
+                    params = {
+                        values = {1}
+                    }
+                }
+            "#,
+            )
+            .eval()
+            .unwrap();
+        let result = from_value(result).unwrap();
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn enum_variant_with_seq() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        #[serde(tag = "id")]
+        enum Variant {
+            Combo { params: ComboParams },
+        }
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct ComboParams {
+            values: Vec<i64>,
+        }
+
+        let expected = Variant::Combo {
+            params: ComboParams { values: vec![] },
+        };
+
+        let lua = Lua::new();
+        let result = lua
+            .load(
+                r#"
+                return {
+                    id = "Combo",
+                    params = {
+                        values = {}
+                    }
+                }
+            "#,
+            )
+            .eval()
+            .unwrap();
+        let result = from_value(result).unwrap();
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn untagged_enum_with_empty_seq() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        #[serde(untagged)]
+        enum Variant {
+            Seq { seq: Vec<u8> },
+        }
+
+        let expected = Variant::Seq { seq: Vec::new() };
+
+        let lua = Lua::new();
+        let result = lua
+            .load(
+                r#"
+                return {
+                    seq = {}
+                }
+            "#,
+            )
+            .eval()
+            .unwrap();
+        let result = from_value(result).unwrap();
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn char_from_single_char_string() {
+        let lua = Lua::new();
+        let value = lua.load(r#"return "a""#).eval().unwrap();
+        let result: char = from_value(value).unwrap();
+        assert_eq!('a', result);
+    }
+
+    #[test]
+    fn char_from_multibyte_char_string() {
+        let lua = Lua::new();
+        let value = lua.load(r#"return "ß""#).eval().unwrap();
+        let result: char = from_value(value).unwrap();
+        assert_eq!('ß', result);
+    }
+
+    #[test]
+    fn char_from_multi_char_string() {
+        let lua = Lua::new();
+        let value = lua.load(r#"return "hello""#).eval().unwrap();
+        let err = from_value::<char>(value).unwrap_err();
+        assert_eq!(
+            r#"expected a single character, found "hello""#,
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn tuple_from_multi_value() {
+        let lua = Lua::new();
+        let values: MultiValue = lua.load(r#"return 1, "two", true"#).eval().unwrap();
+        let result: (i64, String, bool) = from_multi_value(values).unwrap();
+        assert_eq!((1, "two".to_string(), true), result);
+    }
+
+    #[derive(Deserialize, PartialEq, Debug)]
+    #[serde(tag = "type")]
+    enum Shape {
+        Circle { radius: i64 },
+        Rect { width: i64, height: i64 },
+        Empty,
+    }
+
+    #[test]
+    fn internally_tagged_enum_in_vec() {
+        let lua = Lua::new();
+        let value = lua
+            .load(
+                r#"
+                return {
+                    { type = "Circle", radius = 1 },
+                    { type = "Rect", width = 2, height = 3 },
+                    { type = "Empty" },
+                }
+            "#,
+            )
+            .eval()
+            .unwrap();
+        let result: Vec<Shape> = from_value(value).unwrap();
+        assert_eq!(
+            vec![
+                Shape::Circle { radius: 1 },
+                Shape::Rect {
+                    width: 2,
+                    height: 3
+                },
+                Shape::Empty,
+            ],
+            result
+        );
+    }
+
+    #[test]
+    fn internally_tagged_enum_in_map_in_vec() {
+        let lua = Lua::new();
+        let value = lua
+            .load(
+                r#"
+                return {
+                    { a = { type = "Circle", radius = 1 } },
+                    { b = { type = "Empty" } },
+                }
+            "#,
+            )
+            .eval()
+            .unwrap();
+        let result: Vec<HashMap<String, Shape>> = from_value(value).unwrap();
+        let mut first = HashMap::new();
+        first.insert("a".to_string(), Shape::Circle { radius: 1 });
+        let mut second = HashMap::new();
+        second.insert("b".to_string(), Shape::Empty);
+        assert_eq!(vec![first, second], result);
+    }
+
+    #[test]
+    fn internally_tagged_enum_in_struct_in_map_in_vec() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Layer {
+            shapes: Vec<Shape>,
+        }
+
+        let lua = Lua::new();
+        let value = lua
+            .load(
+                r#"
+                return {
+                    {
+                        background = {
+                            shapes = {
+                                { type = "Rect", width = 2, height = 3 },
+                                { type = "Empty" },
+                            },
+                        },
+                    },
+                    { foreground = { shapes = {} } },
+                }
+            "#,
+            )
+            .eval()
+            .unwrap();
+        let result: Vec<HashMap<String, Layer>> = from_value(value).unwrap();
+        let mut first = HashMap::new();
+        first.insert(
+            "background".to_string(),
+            Layer {
+                shapes: vec![
+                    Shape::Rect {
+                        width: 2,
+                        height: 3,
+                    },
+                    Shape::Empty,
+                ],
+            },
+        );
+        let mut second = HashMap::new();
+        second.insert("foreground".to_string(), Layer { shapes: vec![] });
+        assert_eq!(vec![first, second], result);
+    }
+
+    #[test]
+    fn stringy_int_keys() {
+        let lua = Lua::new();
+        let value: mlua::Value = lua
+            .load(r#"return { ["1"] = "a", ["2"] = "b" }"#)
+            .eval()
+            .unwrap();
+
+        assert!(from_value::<HashMap<i64, String>>(value.clone()).is_err());
+
+        let options = DeserializerOptions {
+            parse_stringy_int_keys: true,
+            ..Default::default()
+        };
+        let result: HashMap<i64, String> = from_value_with(value, options).unwrap();
+        let mut expected = HashMap::new();
+        expected.insert(1, "a".to_string());
+        expected.insert(2, "b".to_string());
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn smart_pointers() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Point {
+            x: i64,
+            y: i64,
+        }
+
+        let lua = Lua::new();
+
+        let value = lua.load(r#"return { x = 1, y = 2 }"#).eval().unwrap();
+        let result: Box<Point> = from_value(value).unwrap();
+        assert_eq!(Box::new(Point { x: 1, y: 2 }), result);
+
+        let value = lua.load(r#"return { 1, 2, 3 }"#).eval().unwrap();
+        let result: Rc<[i64]> = from_value(value).unwrap();
+        assert_eq!(Rc::from(vec![1, 2, 3]), result);
+
+        let value = lua.load(r#"return "foo""#).eval().unwrap();
+        let result: Arc<String> = from_value(value).unwrap();
+        assert_eq!(Arc::new("foo".to_string()), result);
+    }
+
+    #[derive(Deserialize, PartialEq, Debug)]
+    enum Message {
+        Move(i64, i64),
+    }
+
+    #[test]
+    fn tuple_variant_from_array() {
+        let lua = Lua::new();
+        let value = lua.load(r#"return { Move = { 1, 2 } }"#).eval().unwrap();
+        let result: Message = from_value(value).unwrap();
+        assert_eq!(Message::Move(1, 2), result);
+    }
+
+    #[test]
+    fn tuple_variant_from_positional_table() {
+        let lua = Lua::new();
+        let value = lua
+            .load(r#"return { Move = { ["2"] = 2, ["1"] = 1 } }"#)
+            .eval()
+            .unwrap();
+        let result: Message = from_value(value).unwrap();
+        assert_eq!(Message::Move(1, 2), result);
+    }
+
+    #[test]
+    fn tuple_variant_from_non_positional_table() {
+        let lua = Lua::new();
+        let value = lua.load(r#"return { Move = { x = 1 } }"#).eval().unwrap();
+        let err = from_value::<Message>(value).unwrap_err();
+        assert_eq!(
+            "expected tuple variant as an array or a table with positional keys, found key `x`",
+            err.to_string()
+        );
+
+        let value = lua
+            .load(r#"return { Move = { [{}] = 1 } }"#)
+            .eval()
+            .unwrap();
+        let err = from_value::<Message>(value).unwrap_err();
+        assert!(err.to_string().ends_with("found key `<table>`"));
+    }
+
+    #[test]
+    fn value_at_path() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Tls {
+            cert: String,
+        }
+
+        let lua = Lua::new();
+        let value: mlua::Value = lua
+            .load(r#"return { server = { tls = { cert = "cert.pem" }, port = 80 } }"#)
+            .eval()
+            .unwrap();
+
+        let result: Tls = from_value_at(value.clone(), &["server", "tls"]).unwrap();
+        assert_eq!(
+            Tls {
+                cert: "cert.pem".to_string()
+            },
+            result
+        );
+
+        let err = from_value_at::<Tls>(value.clone(), &["client", "tls"]).unwrap_err();
+        assert_eq!("missing key `client`", err.to_string());
+
+        let err = from_value_at::<Tls>(value, &["server", "port", "tls"]).unwrap_err();
+        assert_eq!("`server.port` is not a table", err.to_string());
+    }
+
+    #[derive(Deserialize, PartialEq, Debug)]
+    #[serde(bound(deserialize = "T: Deserialize<'de>"))]
+    struct Wrapper<T> {
+        items: Vec<T>,
+    }
+
+    fn load<T: serde::de::DeserializeOwned>(value: mlua::Value) -> crate::Result<T> {
+        from_value(value)
+    }
+
+    #[test]
+    fn generic_struct_with_owned_items() {
+        let lua = Lua::new();
+        let value = lua
+            .load(r#"return { items = { "a", "b" } }"#)
+            .eval()
+            .unwrap();
+        let result: Wrapper<String> = load(value).unwrap();
+        assert_eq!(
+            Wrapper {
+                items: vec!["a".to_string(), "b".to_string()]
+            },
+            result
+        );
+    }
+
+    #[test]
+    fn generic_struct_with_borrowed_items() {
+        let lua = Lua::new();
+        let value: Value = lua
+            .load(r#"return { items = { "a", "b" } }"#)
+            .eval()
+            .unwrap();
+        let result: Wrapper<Cow<'_, str>> = from_value(value.clone()).unwrap();
+        assert_eq!(vec!["a", "b"], result.items);
+        // strings are copied out of Lua, they can't be borrowed
+        assert!(result
+            .items
+            .iter()
+            .all(|item| matches!(item, Cow::Owned(_))));
+        assert!(from_value::<Wrapper<&str>>(value).is_err());
+    }
+
+    #[test]
+    fn verbose_missing_field() {
+        #[derive(Deserialize, Debug)]
+        #[allow(dead_code)]
+        struct Server {
+            host: String,
+            port: u16,
+            timeout: u32,
+        }
+
+        let lua = Lua::new();
+        let value: mlua::Value = lua
+            .load(r#"return { host = "localhost", port = 80 }"#)
+            .eval()
+            .unwrap();
+
+        let err = from_value::<Server>(value.clone()).unwrap_err();
+        assert_eq!("missing field `timeout`", err.to_string());
+
+        let options = DeserializerOptions {
+            verbose_missing_field: true,
+            ..Default::default()
+        };
+        let err = from_value_with::<Server>(value, options).unwrap_err();
+        assert_eq!(
+            "missing field `timeout`; present keys: host, port",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn null_sentinels() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Config {
+            timeout: Option<u32>,
+        }
+
+        let lua = Lua::new();
+        let null = mlua::Value::LightUserData(mlua::LightUserData(std::ptr::null_mut()));
+        lua.globals().set("null", null.clone()).unwrap();
+        let value: mlua::Value = lua.load(r#"return { timeout = null }"#).eval().unwrap();
+
+        assert!(from_value::<Config>(value.clone()).is_err());
+
+        let options = DeserializerOptions {
+            null_sentinels: vec![null],
+            ..Default::default()
+        };
+        let result: Config = from_value_with(value, options).unwrap();
+        assert_eq!(Config { timeout: None }, result);
+    }
+
+    #[test]
+    fn registry_value() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Point {
+            x: i64,
+            y: i64,
+        }
+
+        let lua = Lua::new();
+        let value: mlua::Value = lua.load(r#"return { x = 1, y = 2 }"#).eval().unwrap();
+        let key = lua.create_registry_value(value).unwrap();
+        let result: Point = from_registry_value(&lua, &key).unwrap();
+        assert_eq!(Point { x: 1, y: 2 }, result);
+    }
+
+    #[test]
+    fn u128_map_keys() {
+        let mut map = HashMap::new();
+        map.insert(u128::MAX, "max".to_string());
+        map.insert(1u128, "one".to_string());
+
+        let lua = Lua::new();
+        let value = crate::to_value(&lua, &map).unwrap();
+        let result: HashMap<u128, String> = from_value(value).unwrap();
+        assert_eq!(map, result);
+    }
+
+    #[test]
+    fn strict_table_shapes() {
+        let lua = Lua::new();
+        let value: mlua::Value = lua.load(r#"return { 1, 2, name = "x" }"#).eval().unwrap();
+
+        let result: Vec<i64> = from_value(value.clone()).unwrap();
+        assert_eq!(vec![1, 2], result);
+
+        let options = DeserializerOptions {
+            strict_table_shapes: true,
+            ..Default::default()
+        };
+        let err = from_value_with::<Vec<i64>>(value, options).unwrap_err();
+        assert_eq!(
+            "table mixes array entries and named entries",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn string_floats() {
+        let lua = Lua::new();
+        let options = DeserializerOptions {
+            parse_string_floats: true,
+            ..Default::default()
+        };
+
+        let value: mlua::Value = lua.load(r#"return "2.5""#).eval().unwrap();
+        assert!(from_value::<f64>(value.clone()).is_err());
+        assert_eq!(2.5, from_value_with::<f64>(value, options.clone()).unwrap());
+
+        let value = lua.load(r#"return "1e10""#).eval().unwrap();
+        assert_eq!(
+            1e10,
+            from_value_with::<f32>(value, options.clone()).unwrap()
+        );
+
+        let value = lua.load(r#"return "pi""#).eval().unwrap();
+        assert!(from_value_with::<f64>(value, options.clone()).is_err());
+    }
+
+    #[test]
+    fn json_number_fidelity() {
+        let lua = Lua::new();
+
+        let value = lua.load("return 42").eval().unwrap();
+        let n: serde_json::Number = from_value(value).unwrap();
+        assert!(n.is_i64());
+        assert_eq!(Some(42), n.as_i64());
+
+        let value = lua.load("return 42.5").eval().unwrap();
+        let n: serde_json::Number = from_value(value).unwrap();
+        assert!(n.is_f64());
+        assert_eq!(Some(42.5), n.as_f64());
+
+        let value = lua.load("return { a = 1, b = 1.5 }").eval().unwrap();
+        let json: serde_json::Value = from_value(value).unwrap();
+        assert!(json["a"].is_i64());
+        assert!(json["b"].is_f64());
+    }
+
+    #[test]
+    fn case_insensitive_variants() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        enum Mode {
+            Fast,
+            Slow,
+            Custom { speed: u8 },
+        }
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        enum Ambiguous {
+            #[allow(clippy::upper_case_acronyms)]
+            FAST,
+            Fast,
+        }
+
+        let lua = Lua::new();
+        let options = DeserializerOptions {
+            case_insensitive_variants: true,
+            ..Default::default()
+        };
+
+        let value: mlua::Value = lua.load(r#"return "fast""#).eval().unwrap();
+        assert!(from_value::<Mode>(value.clone()).is_err());
+        assert_eq!(Mode::Fast, from_value_with(value, options.clone()).unwrap());
+
+        let value = lua.load(r#"return "SLOW""#).eval().unwrap();
+        assert_eq!(Mode::Slow, from_value_with(value, options.clone()).unwrap());
+
+        let value = lua
+            .load(r#"return { custom = { speed = 3 } }"#)
+            .eval()
+            .unwrap();
+        assert_eq!(
+            Mode::Custom { speed: 3 },
+            from_value_with(value, options.clone()).unwrap()
+        );
+
+        let value = lua.load(r#"return "Fast""#).eval().unwrap();
+        assert_eq!(
+            Ambiguous::Fast,
+            from_value_with(value, options.clone()).unwrap()
+        );
+
+        let value = lua.load(r#"return "fast""#).eval().unwrap();
+        let err = from_value_with::<Ambiguous>(value, options.clone()).unwrap_err();
+        assert_eq!(
+            "ambiguous variant `fast`, matches both `FAST` and `Fast`",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn byte_buf_from_string() {
+        #[derive(Deserialize, Debug)]
+        struct Blob {
+            #[serde(with = "serde_bytes")]
+            data: Vec<u8>,
+        }
+
+        let lua = Lua::new();
+        let bytes: Vec<u8> = (0..1024 * 1024).map(|i| (i % 256) as u8).collect();
+        let table = lua.create_table().unwrap();
+        table
+            .set("data", lua.create_string(&bytes).unwrap())
+            .unwrap();
+        let blob: Blob = from_value(mlua::Value::Table(table)).unwrap();
+        assert_eq!(bytes, blob.data);
+
+        let value = lua.load("return { data = { 1, 2, 255 } }").eval().unwrap();
+        let blob: Blob = from_value(value).unwrap();
+        assert_eq!(vec![1, 2, 255], blob.data);
+
+        let value = lua.load("return { data = { 1, 256 } }").eval().unwrap();
+        let err = from_value::<Blob>(value).unwrap_err();
+        assert_eq!("invalid value: integer `256`, expected u8", err.to_string());
+    }
+
+    #[test]
+    fn jsontype_marker() {
+        let lua = Lua::new();
+        let options = DeserializerOptions {
+            respect_jsontype_marker: true,
+            ..Default::default()
+        };
+        let marked = |jsontype: &str| -> mlua::Value {
+            lua.load(&format!(
+                r#"return setmetatable({{}}, {{ __jsontype = "{}" }})"#,
+                jsontype
+            ))
+            .eval()
+            .unwrap()
+        };
+
+        let json: serde_json::Value = from_value_with(marked("object"), options.clone()).unwrap();
+        assert_eq!(serde_json::json!({}), json);
+        let map: HashMap<String, i32> = from_value_with(marked("object"), options.clone()).unwrap();
+        assert!(map.is_empty());
+
+        let json: serde_json::Value = from_value_with(marked("array"), options.clone()).unwrap();
+        assert_eq!(serde_json::json!([]), json);
+        let vec: Vec<i32> = from_value_with(marked("array"), options.clone()).unwrap();
+        assert!(vec.is_empty());
+
+        // the marker is ignored unless opted in
+        let json: serde_json::Value = from_value(marked("object")).unwrap();
+        assert_eq!(serde_json::json!([]), json);
+    }
+
+    #[test]
+    fn unit_from_empty_table() {
+        let lua = Lua::new();
+        let options = DeserializerOptions {
+            empty_table_as_unit: true,
+            ..Default::default()
+        };
+
+        from_value::<()>(mlua::Value::Nil).unwrap();
+        from_value_with::<()>(mlua::Value::Nil, options.clone()).unwrap();
+
+        let value: mlua::Value = lua.load("return {}").eval().unwrap();
+        assert!(from_value::<()>(value.clone()).is_err());
+        from_value_with::<()>(value, options.clone()).unwrap();
+
+        let value = lua.load("return { 1 }").eval().unwrap();
+        let err = from_value_with::<()>(value, options.clone()).unwrap_err();
+        assert_eq!("expected unit, found non-empty table", err.to_string());
+    }
+
+    #[test]
+    fn float_keys() {
+        let lua = Lua::new();
+        let value: mlua::Value = lua
+            .load(r#"return { [1.0] = "a", [2.5] = "b" }"#)
+            .eval()
+            .unwrap();
+
+        let map: HashMap<OrderedFloat<f64>, String> = from_value(value.clone()).unwrap();
+        assert_eq!("a", map[&OrderedFloat(1.0)]);
+        assert_eq!("b", map[&OrderedFloat(2.5)]);
+
+        assert!(from_value::<HashMap<i64, String>>(value).is_err());
+        let value: mlua::Value = lua.load(r#"return { [1.0] = "a" }"#).eval().unwrap();
+        let map: HashMap<i64, String> = from_value(value).unwrap();
+        assert_eq!("a", map[&1]);
+
+        let key = |value| MapKeyDeserializer {
+            value,
+            options: Rc::new(DeserializerOptions::default().into()),
+        };
+        assert_eq!(1, i64::deserialize(key(mlua::Value::Number(1.0))).unwrap());
+        assert!(i64::deserialize(key(mlua::Value::Number(1.5))).is_err());
+        assert_eq!(1.0, f64::deserialize(key(mlua::Value::Integer(1))).unwrap());
+    }
+
+    #[test]
+    fn validate_config() {
+        #[derive(Deserialize)]
+        #[allow(dead_code)]
+        struct Config {
+            host: String,
+            port: u16,
+        }
+
+        #[derive(Deserialize)]
+        #[allow(dead_code)]
+        struct Root {
+            servers: Vec<Config>,
+        }
+
+        let lua = Lua::new();
+        let value = lua
+            .load(r#"return { host = "localhost", port = 80 }"#)
+            .eval()
+            .unwrap();
+        validate::<Config>(value).unwrap();
+
+        let value = lua
+            .load(r#"return { host = "localhost", port = "80" }"#)
+            .eval()
+            .unwrap();
+        let err = validate::<Config>(value).unwrap_err();
+        assert_eq!(Some("port"), err.path());
+
+        let value = lua.load(r#"return { port = 80 }"#).eval().unwrap();
+        let err = validate::<Config>(value).unwrap_err();
+        assert_eq!("missing field `host`", err.to_string());
+
+        let value = lua
+            .load(
+                r#"return { servers = { { host = "a", port = 80 }, { host = "b", port = -1 } } }"#,
+            )
+            .eval()
+            .unwrap();
+        let err = validate::<Root>(value).unwrap_err();
+        assert_eq!(Some("servers.2.port"), err.path());
+    }
+
+    #[test]
+    fn optional_vec_nil_vs_empty() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Item {
+            id: u8,
+            tags: Option<Vec<i32>>,
+        }
+
+        let lua = Lua::new();
+        let all_options = DeserializerOptions {
+            respect_jsontype_marker: true,
+            empty_table_as_unit: true,
+            strict_table_shapes: true,
+            ..Default::default()
+        };
+        for (source, expected) in &[
+            ("return { id = 1 }", None),
+            ("return { id = 1, tags = {} }", Some(vec![])),
+            ("return { id = 1, tags = { 1, 2 } }", Some(vec![1, 2])),
+        ] {
+            let value: mlua::Value = lua.load(source).eval().unwrap();
+            let item: Item = from_value(value.clone()).unwrap();
+            assert_eq!(*expected, item.tags);
+            let item: Item = from_value_with(value, all_options.clone()).unwrap();
+            assert_eq!(*expected, item.tags);
+        }
+
+        let value: mlua::Value = lua.load("return {}").eval().unwrap();
+        assert_eq!(Some(vec![]), from_value::<Option<Vec<i32>>>(value).unwrap());
+        assert_eq!(
+            None,
+            from_value::<Option<Vec<i32>>>(mlua::Value::Nil).unwrap()
+        );
+    }
+
+    #[test]
+    fn map_as_pairs() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Server {
+            port: u16,
+        }
+
+        let lua = Lua::new();
+        let value: mlua::Value = lua
+            .load("return { web = { port = 80 }, db = { port = 5432 } }")
+            .eval()
+            .unwrap();
+        // without the option, only the (here: missing) array part is read
+        assert!(from_value::<Vec<(String, Server)>>(value.clone())
+            .unwrap()
+            .is_empty());
+
+        let options = DeserializerOptions {
+            map_as_pairs: true,
+            ..Default::default()
+        };
+        let mut pairs: Vec<(String, Server)> = from_value_with(value, options.clone()).unwrap();
+        pairs.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            vec![
+                ("db".to_string(), Server { port: 5432 }),
+                ("web".to_string(), Server { port: 80 }),
+            ],
+            pairs
+        );
+
+        // arrays are still deserialized element-wise
+        let value = lua.load("return { { 1, 2 } }").eval().unwrap();
+        let pairs: Vec<(i32, i32)> = from_value_with(value, options.clone()).unwrap();
+        assert_eq!(vec![(1, 2)], pairs);
+
+        // the length counts the pairs visited and those left over
+        let value: Value = lua.load("return { a = 1, b = 2, c = 3 }").eval().unwrap();
+        let err = from_value_with::<[(String, u8); 1]>(value, options.clone()).unwrap_err();
+        assert_eq!(
+            "invalid length 3, expected fewer elements in array",
+            err.to_string()
+        );
+
+        let value: Value = lua.load("return { 1, a = 2 }").eval().unwrap();
+        let strict = DeserializerOptions {
+            strict_table_shapes: true,
+            ..options
+        };
+        let err = from_value_with::<Vec<(serde_json::Value, u8)>>(value, strict).unwrap_err();
+        assert_eq!(
+            "table mixes array entries and named entries",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn owned_string() {
+        let lua = Lua::new();
+        let value = mlua::Value::String(lua.create_string("héllo").unwrap());
+        assert_eq!("héllo", from_value::<String>(value).unwrap());
+
+        let value = mlua::Value::String(lua.create_string(b"ab\xffcd").unwrap());
+        let err = from_value::<String>(value).unwrap_err();
+        assert_eq!("invalid UTF-8 in string at byte offset 2", err.to_string());
+    }
+
+    #[test]
+    fn dynamic_coercion() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Config {
+            port: u16,
+            ratio: f64,
+            timeout: f64,
+            name: String,
+            enabled: bool,
+            count: i32,
+            initial: char,
+            limits: HashMap<u8, String>,
+        }
+
+        let lua = Lua::new();
+        let value: mlua::Value = lua
+            .load(
+                r#"return {
+                    port = "8080",
+                    ratio = "0.5",
+                    timeout = 30,
+                    name = 42,
+                    enabled = 1,
+                    count = 3.0,
+                    initial = "x",
+                    limits = { ["1"] = 2.5 },
+                }"#,
+            )
+            .eval()
+            .unwrap();
+        assert!(from_value::<Config>(value.clone()).is_err());
+
+        let config: Config =
+            from_value_with(value, DeserializerOptions::dynamic_coercion()).unwrap();
+        let mut limits = HashMap::new();
+        limits.insert(1, "2.5".to_string());
+        assert_eq!(
+            Config {
+                port: 8080,
+                ratio: 0.5,
+                timeout: 30.0,
+                name: "42".to_string(),
+                enabled: true,
+                count: 3,
+                initial: 'x',
+                limits,
+            },
+            config
+        );
+
+        let value = lua.load(r#"return "4x""#).eval().unwrap();
+        assert!(from_value_with::<u16>(value, DeserializerOptions::dynamic_coercion()).is_err());
+    }
+
+    #[test]
+    fn enum_discriminant_as_index() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        enum Message {
+            Ping,
+            Move { x: i32, y: i32 },
+            Say { text: String },
+        }
+
+        let lua = Lua::new();
+        let options = DeserializerOptions {
+            enum_representation: EnumRepresentation::InternallyTagged {
+                tag: "type".to_string(),
+            },
+            enum_discriminant_as_index: true,
+            ..Default::default()
+        };
+
+        let value: mlua::Value = lua
+            .load(r#"return { type = 2, text = "hi" }"#)
+            .eval()
+            .unwrap();
+        assert_eq!(
+            Message::Say {
+                text: "hi".to_string()
+            },
+            from_value_with(value, options.clone()).unwrap()
+        );
+
+        let value = lua
+            .load("return { type = 1, x = 1, y = 2 }")
+            .eval()
+            .unwrap();
+        assert_eq!(
+            Message::Move { x: 1, y: 2 },
+            from_value_with(value, options.clone()).unwrap()
+        );
+
+        let value = lua.load("return 0").eval().unwrap();
+        assert_eq!(
+            Message::Ping,
+            from_value_with(value, options.clone()).unwrap()
+        );
+
+        let value = lua.load("return { type = 3 }").eval().unwrap();
+        let err = from_value_with::<Message>(value, options.clone()).unwrap_err();
+        assert_eq!(
+            "enum discriminant 3 out of range, expected 0..3",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn enum_tag_after_content() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        enum Token {
+            Number { value: i64 },
+            Word { text: String, upper: bool },
+        }
+
+        let lua = Lua::new();
+        let options = DeserializerOptions {
+            enum_representation: EnumRepresentation::InternallyTagged {
+                tag: "type".to_string(),
+            },
+            ..Default::default()
+        };
+
+        let value = lua
+            .load(r#"local t = { value = 5 }; t.type = "Number"; return t"#)
+            .eval()
+            .unwrap();
+        assert_eq!(
+            Token::Number { value: 5 },
+            from_value_with(value, options.clone()).unwrap()
+        );
+
+        let value = lua
+            .load(r#"local t = { text = "a", upper = true }; t.type = "Word"; return t"#)
+            .eval()
+            .unwrap();
+        assert_eq!(
+            Token::Word {
+                text: "a".to_string(),
+                upper: true
+            },
+            from_value_with(value, options.clone()).unwrap()
+        );
+    }
+
+    #[test]
+    fn deserialize_with_functions() {
+        fn sum<'de, D>(deserializer: D) -> Result<i64, D::Error>
+        where
+            D: serde::Deserializer<'de>,
+        {
+            struct SumVisitor;
+
+            impl<'de> serde::de::Visitor<'de> for SumVisitor {
+                type Value = i64;
+
+                fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                    f.write_str("a sequence of integers")
+                }
+
+                fn visit_seq<A>(self, mut seq: A) -> Result<i64, A::Error>
+                where
+                    A: serde::de::SeqAccess<'de>,
+                {
+                    let mut sum = 0;
+                    while let Some(v) = seq.next_element::<i64>()? {
+                        sum += v;
+                    }
+                    Ok(sum)
+                }
+            }
+
+            serde::Deserializer::deserialize_seq(deserializer, SumVisitor)
+        }
+
+        fn upper<'de, D>(deserializer: D) -> Result<String, D::Error>
+        where
+            D: serde::Deserializer<'de>,
+        {
+            struct UpperVisitor;
+
+            impl<'de> serde::de::Visitor<'de> for UpperVisitor {
+                type Value = String;
+
+                fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                    f.write_str("a string")
+                }
+
+                fn visit_str<E>(self, v: &str) -> Result<String, E> {
+                    Ok(v.to_uppercase())
+                }
+            }
+
+            serde::Deserializer::deserialize_str(deserializer, UpperVisitor)
+        }
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Stats {
+            #[serde(deserialize_with = "sum")]
+            total: i64,
+            #[serde(deserialize_with = "upper")]
+            name: String,
+        }
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Id(u32);
+
+        let lua = Lua::new();
+        let value: mlua::Value = lua
+            .load(r#"return { total = { 1, 2, 3 }, name = "abc" }"#)
+            .eval()
+            .unwrap();
+        assert_eq!(
+            Stats {
+                total: 6,
+                name: "ABC".to_string()
+            },
+            from_value(value).unwrap()
+        );
+
+        let value = lua
+            .load(r#"return { total = 1, name = "abc" }"#)
+            .eval()
+            .unwrap();
+        assert!(from_value::<Stats>(value).is_err());
+
+        let value = lua.load("return 7").eval().unwrap();
+        assert_eq!(Id(7), from_value(value).unwrap());
+    }
+
+    #[test]
+    fn newtype_name_in_errors() {
+        #[derive(Deserialize, Debug)]
+        struct Port(u16);
+
+        let lua = Lua::new();
+        let err = from_value::<Port>(Value::Integer(70000)).unwrap_err();
+        assert_eq!(
+            "failed to deserialize Port: invalid value: integer `70000`, expected u16",
+            err.to_string()
+        );
+        let port = from_value::<Port>(lua.load("return 8080").eval().unwrap()).unwrap();
+        assert_eq!(8080, port.0);
+
+        #[derive(Deserialize, Debug)]
+        struct Id(std::num::NonZeroU32);
+
+        #[derive(Deserialize, Debug)]
+        struct Outer {
+            id: Id,
+        }
+
+        let outer = from_value::<Outer>(lua.load("return { id = 5 }").eval().unwrap()).unwrap();
+        assert_eq!(5, outer.id.0.get());
+        let err = from_value::<Outer>(lua.load("return { id = 0 }").eval().unwrap()).unwrap_err();
+        assert_eq!(Some("id"), err.path());
+        assert_eq!("`id`: value must be non-zero", err.to_string());
+        let err = from_value::<Id>(lua.load("return 0").eval().unwrap()).unwrap_err();
+        assert!(matches!(err, crate::Error::NonZero));
+    }
+
+    #[test]
+    fn map_access_edge_cases() {
+        use serde::de::{Deserializer as _, MapAccess, Visitor};
+        use std::fmt;
+
+        // Reads both keys before their values.
+        struct KeysFirst;
+
+        impl<'de> Visitor<'de> for KeysFirst {
+            type Value = Vec<(String, i64)>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a map")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let first: String = map.next_key()?.unwrap();
+                let second: String = map.next_key()?.unwrap();
+                let entries = vec![(first, map.next_value()?), (second, map.next_value()?)];
+                assert!(map.next_key::<String>()?.is_none());
+                Ok(entries)
+            }
+        }
+
+        // Reads a value without a key.
+        struct ValueFirst;
+
+        impl<'de> Visitor<'de> for ValueFirst {
+            type Value = i64;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a map")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                map.next_value()
+            }
+        }
+
+        let lua = Lua::new();
+        let value: Value = lua.load("return { a = 1, b = 2 }").eval().unwrap();
+        let mut entries = super::Deserializer::from_value(value)
+            .deserialize_map(KeysFirst)
+            .unwrap();
+        entries.sort();
+        assert_eq!(vec![("a".to_string(), 1), ("b".to_string(), 2)], entries);
+
+        let value: Value = lua.load("return { a = 1 }").eval().unwrap();
+        let err = super::Deserializer::from_value(value)
+            .deserialize_map(ValueFirst)
+            .unwrap_err();
+        assert_eq!(
+            "value is missing, `next_value` must follow a call to `next_key`",
+            err.to_string()
+        );
+
+        let value: Value = lua.load("return { a = 1, b = 2 }").eval().unwrap();
+        let map: HashMap<String, i64> = from_value(value).unwrap();
+        assert_eq!(Some(&2), map.get("b"));
+    }
+
+    #[test]
+    fn number_string_policy() {
+        use super::NumberStringPolicy;
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Item {
+            count: u32,
+            ratio: f64,
+            label: String,
+        }
+
+        let lua = Lua::new();
+        let options = |number_string_policy| DeserializerOptions {
+            number_string_policy,
+            ..Default::default()
+        };
+
+        let strings: Value = lua
+            .load(r#"return { count = "10", ratio = "0.5", label = "10" }"#)
+            .eval()
+            .unwrap();
+        let numbers: Value = lua
+            .load(r#"return { count = 10, ratio = 0.5, label = 10 }"#)
+            .eval()
+            .unwrap();
+        let expected = Item {
+            count: 10,
+            ratio: 0.5,
+            label: "10".to_string(),
+        };
+
+        let strict = options(NumberStringPolicy::Strict);
+        assert!(from_value_with::<Item>(strings.clone(), strict.clone()).is_err());
+        assert!(from_value_with::<Item>(numbers.clone(), strict).is_err());
+
+        let prefer_number = options(NumberStringPolicy::PreferNumber);
+        assert_eq!(
+            expected,
+            from_value_with(strings.clone(), prefer_number.clone()).unwrap()
+        );
+        assert!(from_value_with::<Item>(numbers.clone(), prefer_number).is_err());
+
+        let prefer_string = options(NumberStringPolicy::PreferString);
+        assert_eq!(
+            expected,
+            from_value_with::<Item>(numbers, prefer_string.clone()).unwrap()
+        );
+        assert!(from_value_with::<Item>(strings, prefer_string).is_err());
+    }
+
+    #[cfg(feature = "bstr")]
+    #[test]
+    fn bstr_from_any_string() {
+        use bstr::BString;
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Line {
+            text: BString,
+        }
+
+        let lua = Lua::new();
+        let value: Value = lua.load(r#"return { text = "héllo" }"#).eval().unwrap();
+        let line: Line = from_value(value).unwrap();
+        assert_eq!(BString::from("héllo"), line.text);
+
+        let value: Value = lua.load(r#"return { text = "a\255b" }"#).eval().unwrap();
+        let line: Line = from_value(value).unwrap();
+        assert_eq!(BString::from(&b"a\xffb"[..]), line.text);
+    }
+
+    #[test]
+    fn pointer_width_range() {
+        use super::check_pointer_width;
+
+        // simulate a 32-bit target
+        let err = check_pointer_width(1 << 32, &"usize", 32).unwrap_err();
+        assert_eq!(
+            "value 4294967296 out of range for usize on this platform",
+            err.to_string()
+        );
+        assert!(check_pointer_width(-1, &"usize", 32).is_err());
+        assert!(check_pointer_width(u32::MAX as i64, &"usize", 32).is_ok());
+        assert!(check_pointer_width(i32::MIN as i64, &"isize", 32).is_ok());
+        assert!(check_pointer_width(i32::MIN as i64 - 1, &"isize", 32).is_err());
+        assert!(check_pointer_width(1 << 32, &"u64", 32).is_ok());
+
+        let lua = Lua::new();
+        let value: Value = lua.load("return 4294967296").eval().unwrap();
+        if usize::BITS < 64 {
+            let err = from_value::<usize>(value).unwrap_err();
+            assert!(err.to_string().contains("on this platform"));
+        } else {
+            assert_eq!(1 << 32, from_value::<usize>(value).unwrap());
+        }
+    }
+
+    #[test]
+    fn duplicate_fields() {
+        #[derive(Deserialize, PartialEq, Eq, Hash, Debug)]
+        #[serde(untagged)]
+        enum Key {
+            Int(i64),
+            Str(String),
+        }
+
+        #[derive(Deserialize, Debug)]
+        struct Config {
+            name: String,
+            #[serde(flatten)]
+            extra: HashMap<Key, String>,
+        }
+
+        // A struct that keeps the value visited last, unlike derived ones.
+        #[derive(Debug)]
+        struct Fields(HashMap<String, String>);
+
+        impl<'de> Deserialize<'de> for Fields {
+            fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+            where
+                D: serde::Deserializer<'de>,
+            {
+                struct FieldsVisitor;
+
+                impl<'de> serde::de::Visitor<'de> for FieldsVisitor {
+                    type Value = Fields;
+
+                    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                        formatter.write_str("struct Fields")
+                    }
+
+                    fn visit_map<A>(self, mut map: A) -> Result<Fields, A::Error>
+                    where
+                        A: serde::de::MapAccess<'de>,
+                    {
+                        let mut fields = HashMap::new();
+                        while let Some((key, value)) = map.next_entry()? {
+                            fields.insert(key, value);
+                        }
+                        Ok(Fields(fields))
+                    }
+                }
+
+                deserializer.deserialize_struct("Fields", &["name"], FieldsVisitor)
+            }
+        }
+
+        let lua = Lua::new();
+        let value: Value = lua
+            .load(
+                r#"
+                local keys = { "name", "name" }
+                return setmetatable({ name = "v0" }, {
+                    __pairs = function(t)
+                        local i = 0
+                        return function()
+                            i = i + 1
+                            if keys[i] then
+                                return keys[i], "v" .. i
+                            end
+                        end, t, nil
+                    end,
+                })
+                "#,
+            )
+            .eval()
+            .unwrap();
+        let options = DeserializerOptions {
+            respect_pairs_metamethod: true,
+            ..Default::default()
+        };
+        let fields: Fields = from_value_with(value.clone(), options.clone()).unwrap();
+        assert_eq!(Some("v2"), fields.0.get("name").map(String::as_str));
+
+        let strict = DeserializerOptions {
+            error_on_duplicate_field: true,
+            ..options
+        };
+        let err = from_value_with::<Fields>(value, strict.clone()).unwrap_err();
+        assert_eq!("duplicate field `name`", err.to_string());
+
+        // `1` and `"1"` are the same key to `parse_stringy_int_keys`
+        let value: Value = lua
+            .load(r#"return { [1] = "a", ["1"] = "b" }"#)
+            .eval()
+            .unwrap();
+        let stringy = DeserializerOptions {
+            parse_stringy_int_keys: true,
+            ..Default::default()
+        };
+        let map: HashMap<i64, String> = from_value_with(value.clone(), stringy.clone()).unwrap();
+        assert_eq!(1, map.len());
+        let stringy_strict = DeserializerOptions {
+            error_on_duplicate_field: true,
+            ..stringy
+        };
+        let err =
+            from_value_with::<HashMap<i64, String>>(value, stringy_strict.clone()).unwrap_err();
+        assert_eq!("duplicate field `1`", err.to_string());
+
+        // flattened fields are buffered before being deserialized
+        let value: Value = lua
+            .load(r#"return { name = "x", [2] = "a", ["2"] = "b" }"#)
+            .eval()
+            .unwrap();
+        let err = from_value_with::<Config>(value.clone(), stringy_strict).unwrap_err();
+        assert_eq!("duplicate field `2`", err.to_string());
+
+        // otherwise `2` and `"2"` are distinct keys
+        let map: HashMap<Key, String> = from_value_with(value.clone(), strict.clone()).unwrap();
+        assert_eq!(3, map.len());
+        let config: Config = from_value_with(value, strict).unwrap();
+        assert_eq!("x", config.name);
+        assert_eq!(
+            Some("a"),
+            config.extra.get(&Key::Int(2)).map(String::as_str)
+        );
+        assert_eq!(
+            Some("b"),
+            config
+                .extra
+                .get(&Key::Str("2".to_string()))
+                .map(String::as_str)
+        );
+    }
+
+    #[cfg(feature = "arrayvec")]
+    #[test]
+    fn arrayvec_capacity() {
+        use arrayvec::ArrayVec;
+
+        let lua = Lua::new();
+        let value: Value = lua.load("return { 1, 2, 3 }").eval().unwrap();
+        let fits: ArrayVec<i64, 3> = from_value(value.clone()).unwrap();
+        assert_eq!(&[1, 2, 3], fits.as_slice());
+
+        let err = from_value::<ArrayVec<i64, 2>>(value).unwrap_err();
+        assert_eq!(
+            "invalid length 3, expected an array with no more than 2 items",
+            err.to_string()
+        );
+    }
+
+    #[cfg(feature = "either")]
+    #[test]
+    fn either_left_or_right() {
+        use either::Either;
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Limit {
+            #[serde(with = "either::serde_untagged")]
+            max: Either<i64, String>,
+        }
+
+        let lua = Lua::new();
+        let value = lua.load("return { max = 10 }").eval().unwrap();
+        assert_eq!(Either::Left(10), from_value::<Limit>(value).unwrap().max);
+        let value = lua.load(r#"return { max = "unlimited" }"#).eval().unwrap();
+        assert_eq!(
+            Either::Right("unlimited".to_string()),
+            from_value::<Limit>(value).unwrap().max
+        );
+        let value = lua.load("return { max = true }").eval().unwrap();
+        assert!(from_value::<Limit>(value).is_err());
+
+        // without `serde_untagged`, `Either` is externally tagged
+        let value = lua.load("return { Left = 10 }").eval().unwrap();
+        assert_eq!(
+            Either::Left(10),
+            from_value::<Either<i64, String>>(value).unwrap()
+        );
+        let value = lua
+            .load(r#"return { Right = "unlimited" }"#)
+            .eval()
+            .unwrap();
+        assert_eq!(
+            Either::Right("unlimited".to_string()),
+            from_value::<Either<i64, String>>(value).unwrap()
+        );
+    }
+
+    #[cfg(feature = "smallvec")]
+    #[test]
+    fn smallvec_spills() {
+        use smallvec::SmallVec;
+
+        let lua = Lua::new();
+        let value: Value = lua.load("return { 1, 2 }").eval().unwrap();
+        let inline: SmallVec<[i64; 2]> = from_value(value).unwrap();
+        assert!(!inline.spilled());
+
+        let value: Value = lua.load("return { 1, 2, 3 }").eval().unwrap();
+        let spilled: SmallVec<[i64; 2]> = from_value(value).unwrap();
+        assert!(spilled.spilled());
+        assert_eq!(&[1, 2, 3], spilled.as_slice());
+    }
+
+    #[test]
+    fn respect_pairs_metamethod() {
+        let lua = Lua::new();
+        let value: Value = lua
+            .load(
+                r#"
+                local keys = { "a", "b" }
+                return setmetatable({}, {
+                    __pairs = function(t)
+                        local i = 0
+                        return function()
+                            i = i + 1
+                            if keys[i] then
+                                return keys[i], i * 10
+                            end
+                        end, t, nil
+                    end,
+                })
+                "#,
+            )
+            .eval()
+            .unwrap();
+
+        let options = DeserializerOptions {
+            respect_pairs_metamethod: true,
+            ..Default::default()
+        };
+        let map: HashMap<String, i64> = from_value_with(value.clone(), options).unwrap();
+        let mut expected = HashMap::new();
+        expected.insert("a".to_string(), 10);
+        expected.insert("b".to_string(), 20);
+        assert_eq!(expected, map);
+
+        let map: HashMap<String, i64> = from_value(value).unwrap();
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn nil_as_false() {
+        let options = DeserializerOptions {
+            nil_as_false: true,
+            ..Default::default()
+        };
+        assert!(!from_value_with::<bool>(Value::Nil, options.clone()).unwrap());
+        assert!(from_value::<bool>(Value::Nil).is_err());
+
+        // distinct from `Option<bool>`, which stays `None`
+        let flag: Option<bool> = from_value_with(Value::Nil, options.clone()).unwrap();
+        assert_eq!(None, flag);
+        assert!(from_value_with::<bool>(Value::Integer(1), options).is_err());
+    }
+
+    #[test]
+    fn float_as_bool() {
+        let options = DeserializerOptions {
+            int_as_bool: true,
+            ..Default::default()
+        };
+        assert!(from_value_with::<bool>(Value::Number(1.0), options.clone()).unwrap());
+        assert!(!from_value_with::<bool>(Value::Number(0.0), options.clone()).unwrap());
+        let err = from_value_with::<bool>(Value::Number(0.5), options.clone()).unwrap_err();
+        assert_eq!(
+            "invalid value: floating point `0.5`, expected 0 or 1",
+            err.to_string()
+        );
+        assert!(from_value_with::<bool>(Value::Number(2.0), options).is_err());
+        assert!(from_value::<bool>(Value::Number(1.0)).is_err());
+    }
+
+    #[test]
+    fn seq_size_hint() {
+        use serde::de::{SeqAccess, Visitor};
+        use std::fmt;
+
+        struct Hint(Option<usize>, usize);
+
+        impl<'de> Deserialize<'de> for Hint {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                struct HintVisitor;
 
-    fn variant_seed<T>(self, seed: T) -> Result<(T::Value, Self::Variant)>
-    where
-        T: DeserializeSeed<'de>,
-    {
-        let variant = self.variant.into_deserializer();
-        let variant_access = VariantDeserializer { value: self.value };
-        seed.deserialize(variant).map(|v| (v, variant_access))
-    }
-}
+                impl<'de> Visitor<'de> for HintVisitor {
+                    type Value = Hint;
 
-struct VariantDeserializer<'lua> {
-    value: Option<Value<'lua>>,
-}
+                    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                        formatter.write_str("a sequence")
+                    }
 
-impl<'lua, 'de> VariantAccess<'de> for VariantDeserializer<'lua> {
-    type Error = Error;
+                    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Hint, A::Error> {
+                        let hint = seq.size_hint();
+                        let mut count = 0;
+                        while seq.next_element::<i64>()?.is_some() {
+                            count += 1;
+                        }
+                        Ok(Hint(hint, count))
+                    }
+                }
 
-    fn unit_variant(self) -> Result<()> {
-        match self.value {
-            Some(_) => Err(serde::de::Error::invalid_type(
-                serde::de::Unexpected::NewtypeVariant,
-                &"unit variant",
-            )),
-            None => Ok(()),
+                deserializer.deserialize_seq(HintVisitor)
+            }
         }
+
+        let lua = Lua::new();
+        let value: Value = lua
+            .load("local t = {} for i = 1, 10000 do t[i] = i end return t")
+            .eval()
+            .unwrap();
+        let hint: Hint = from_value(value.clone()).unwrap();
+        assert_eq!(Some(10000), hint.0);
+        assert_eq!(10000, hint.1);
+        assert_eq!(10000, from_value::<Vec<i64>>(value).unwrap().len());
+
+        let value: Value = lua
+            .load("local t = {} for i = 1, 100000 do t[i] = i end return t")
+            .eval()
+            .unwrap();
+        let hint: Hint = from_value(value).unwrap();
+        assert_eq!(Some(super::MAX_SIZE_HINT), hint.0);
+        assert_eq!(100000, hint.1);
     }
 
-    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value>
-    where
-        T: DeserializeSeed<'de>,
-    {
-        match self.value {
-            Some(value) => seed.deserialize(Deserializer { value }),
-            None => Err(serde::de::Error::invalid_type(
-                serde::de::Unexpected::UnitVariant,
-                &"newtype variant",
-            )),
+    #[test]
+    fn flatten_optional_struct() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Auth {
+            user: String,
+            password: String,
         }
-    }
 
-    fn tuple_variant<V>(self, _len: usize, visitor: V) -> Result<V::Value>
-    where
-        V: Visitor<'de>,
-    {
-        match self.value {
-            Some(value) => serde::Deserializer::deserialize_seq(Deserializer { value }, visitor),
-            None => Err(serde::de::Error::invalid_type(
-                serde::de::Unexpected::UnitVariant,
-                &"tuple variant",
-            )),
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Connection {
+            host: String,
+            #[serde(flatten)]
+            auth: Option<Auth>,
         }
+
+        let lua = Lua::new();
+        let value = lua
+            .load(r#"return { host = "db", user = "admin", password = "secret" }"#)
+            .eval()
+            .unwrap();
+        assert_eq!(
+            Connection {
+                host: "db".to_string(),
+                auth: Some(Auth {
+                    user: "admin".to_string(),
+                    password: "secret".to_string(),
+                }),
+            },
+            from_value(value).unwrap()
+        );
+
+        let value = lua.load(r#"return { host = "db" }"#).eval().unwrap();
+        assert_eq!(
+            Connection {
+                host: "db".to_string(),
+                auth: None,
+            },
+            from_value(value).unwrap()
+        );
     }
 
-    fn struct_variant<V>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value>
-    where
-        V: Visitor<'de>,
-    {
-        match self.value {
-            Some(value) => serde::Deserializer::deserialize_map(Deserializer { value }, visitor),
-            None => Err(serde::de::Error::invalid_type(
-                serde::de::Unexpected::UnitVariant,
-                &"struct variant",
-            )),
+    #[test]
+    fn untagged_enum_in_seq() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        #[serde(untagged)]
+        enum Val {
+            Int(i64),
+            Float(f64),
+            Text(String),
         }
+
+        let lua = Lua::new();
+        let value = lua
+            .load(r#"return { 1, "two", 3, 4.5, 2^53 }"#)
+            .eval()
+            .unwrap();
+        assert_eq!(
+            vec![
+                Val::Int(1),
+                Val::Text("two".to_string()),
+                Val::Int(3),
+                Val::Float(4.5),
+                Val::Int(1 << 53),
+            ],
+            from_value::<Vec<Val>>(value).unwrap()
+        );
     }
-}
 
-fn is_seq(val: Table) -> Result<bool> {
-    let mut next_key = 1;
-    for pair in val.pairs::<Value, Value>() {
-        let (key, _) = pair?;
-        if key != Value::Integer(next_key) {
-            return Ok(false);
+    #[test]
+    fn non_utf8_keys() {
+        #[derive(Deserialize, Debug)]
+        #[allow(dead_code)]
+        struct Config {
+            name: Option<String>,
         }
-        next_key += 1;
+
+        let lua = Lua::new();
+        let value: Value = lua.load(r#"return { ["na\255me"] = "x" }"#).eval().unwrap();
+        let err = from_value::<Config>(value.clone()).unwrap_err();
+        assert_eq!(
+            "struct field key is not valid UTF-8: \"na\u{fffd}me\"",
+            err.to_string()
+        );
+        let err = from_value::<HashMap<String, String>>(value).unwrap_err();
+        assert!(err.to_string().starts_with("map key is not valid UTF-8"));
+
+        #[derive(Deserialize, Debug)]
+        enum Mode {
+            Fast,
+        }
+        let value: Value = lua.load(r#"return "F\255ast""#).eval().unwrap();
+        let err = from_value::<Mode>(value).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("enum variant name is not valid UTF-8"));
     }
 
-    Ok(true)
-}
+    #[test]
+    fn max_string_len() {
+        #[derive(Deserialize, Debug)]
+        struct Message {
+            text: String,
+            #[serde(with = "serde_bytes")]
+            payload: Vec<u8>,
+        }
 
-#[cfg(test)]
-mod test {
-    use super::from_value;
-    use mlua::Lua;
-    use serde::Deserialize;
+        let lua = Lua::new();
+        let value: Value = lua
+            .load(r#"return { text = string.rep("x", 1000000), payload = "abc" }"#)
+            .eval()
+            .unwrap();
+        let options = DeserializerOptions {
+            max_string_len: Some(1024),
+            ..Default::default()
+        };
+        let err = from_value_with::<Message>(value.clone(), options.clone()).unwrap_err();
+        assert_eq!(
+            "string of 1000000 bytes exceeds max_string_len of 1024",
+            err.to_string()
+        );
+        let message = from_value::<Message>(value).unwrap();
+        assert_eq!(1000000, message.text.len());
+        assert_eq!(b"abc", &message.payload[..]);
+
+        let value: Value = lua
+            .load(r#"return { text = "hi", payload = string.rep("x", 2000) }"#)
+            .eval()
+            .unwrap();
+        assert!(from_value_with::<Message>(value, options.clone()).is_err());
+
+        // also for values of any type and for map keys
+        let value: Value = lua.load(r#"return string.rep("x", 2000)"#).eval().unwrap();
+        let err = from_value_with::<serde_json::Value>(value, options.clone()).unwrap_err();
+        assert_eq!(
+            "string of 2000 bytes exceeds max_string_len of 1024",
+            err.to_string()
+        );
+        let value: Value = lua
+            .load(r#"return { [string.rep("x", 2000)] = 1 }"#)
+            .eval()
+            .unwrap();
+        let err = from_value_with::<HashMap<String, u8>>(value, options).unwrap_err();
+        assert_eq!(
+            "string of 2000 bytes exceeds max_string_len of 1024",
+            err.to_string()
+        );
+    }
 
     #[test]
-    fn enum_variant_with_empty_seq() {
+    fn single_field_tuple_struct_from_scalar() {
+        // derived `Deserialize` for single field tuple structs uses `deserialize_newtype_struct`
         #[derive(Deserialize, PartialEq, Debug)]
-        #[serde(tag = "id")]
-        enum Variant {
-            Combo { params: ComboParams },
+        struct Meters(f64);
+
+        struct Feet(f64);
+
+        impl<'de> Deserialize<'de> for Feet {
+            fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+            where
+                D: serde::Deserializer<'de>,
+            {
+                struct FeetVisitor;
+
+                impl<'de> serde::de::Visitor<'de> for FeetVisitor {
+                    type Value = Feet;
+
+                    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                        f.write_str("tuple struct Feet")
+                    }
+
+                    fn visit_seq<A>(self, mut seq: A) -> Result<Feet, A::Error>
+                    where
+                        A: serde::de::SeqAccess<'de>,
+                    {
+                        let v = seq
+                            .next_element()?
+                            .ok_or_else(|| serde::de::Error::invalid_length(0, &self))?;
+                        Ok(Feet(v))
+                    }
+                }
+
+                deserializer.deserialize_tuple_struct("Feet", 1, FeetVisitor)
+            }
         }
 
         #[derive(Deserialize, PartialEq, Debug)]
-        struct ComboParams {
-            values: Vec<i64>,
+        struct Point(i64, i64);
+
+        assert_eq!(Meters(5.0), from_value(Value::Number(5.0)).unwrap());
+        assert_eq!(5.5, from_value::<Feet>(Value::Number(5.5)).unwrap().0);
+
+        let lua = Lua::new();
+        let value = lua.load("return { 5.5 }").eval().unwrap();
+        assert_eq!(5.5, from_value::<Feet>(value).unwrap().0);
+        let value = lua.load("return { 1, 2 }").eval().unwrap();
+        assert_eq!(Point(1, 2), from_value(value).unwrap());
+        assert!(from_value::<Point>(Value::Integer(1)).is_err());
+    }
+
+    #[test]
+    fn untagged_scalar_or_struct() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        #[serde(untagged)]
+        enum Setting {
+            Simple(bool),
+            Detailed { enabled: bool, level: u8 },
         }
 
-        let expected = Variant::Combo {
-            params: ComboParams { values: vec![1] },
+        let lua = Lua::new();
+        let value = lua
+            .load("return { a = true, b = { enabled = true, level = 3 } }")
+            .eval()
+            .unwrap();
+        let settings: HashMap<String, Setting> = from_value(value).unwrap();
+        assert_eq!(Some(&Setting::Simple(true)), settings.get("a"));
+        assert_eq!(
+            Some(&Setting::Detailed {
+                enabled: true,
+                level: 3
+            }),
+            settings.get("b")
+        );
+
+        let value = lua.load("return { enabled = false }").eval().unwrap();
+        assert!(from_value::<Setting>(value).is_err());
+    }
+
+    #[test]
+    fn classify_tables() {
+        let lua = Lua::new();
+        let classify = |code: &str| {
+            let table: mlua::Table = lua.load(code).eval().unwrap();
+            classify_table(&table).unwrap()
         };
+        assert_eq!(TableKind::Seq, classify("return { 1, 2, 3 }"));
+        assert_eq!(TableKind::Seq, classify("return {}"));
+        assert_eq!(TableKind::Map, classify("return { a = 1 }"));
+        assert_eq!(TableKind::Map, classify("return { 1, 2, a = 3 }"));
+        assert_eq!(TableKind::Map, classify("return { [1] = 1, [3] = 3 }"));
+        assert_eq!(TableKind::Map, classify("return { [0] = 0, [1] = 1 }"));
+    }
+
+    #[test]
+    fn trim_strings() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Env {
+            name: String,
+            tags: HashMap<String, String>,
+            #[serde(with = "serde_bytes")]
+            raw: Vec<u8>,
+        }
 
         let lua = Lua::new();
-        let result = lua
-            .load(
-                r#"
-                return {
-                    id = "Combo",
-                    params = {
-                        values = {1}
-                    }
-                }
-            "#,
-            )
+        let value: Value = lua
+            .load(r#"return { name = " prod\n", tags = { [" k "] = " v " }, raw = " x " }"#)
             .eval()
             .unwrap();
-        let result = from_value(result).unwrap();
-        assert_eq!(expected, result);
+        let env: Env = from_value(value.clone()).unwrap();
+        assert_eq!(" prod\n", env.name);
+
+        let options = DeserializerOptions {
+            trim_strings: true,
+            ..Default::default()
+        };
+        let env: Env = from_value_with(value, options).unwrap();
+        assert_eq!("prod", env.name);
+        assert_eq!(Some("v"), env.tags.get(" k ").map(String::as_str));
+        assert_eq!(b" x ".to_vec(), env.raw);
     }
 
     #[test]
-    fn enum_variant_with_seq() {
+    fn non_zero_ints() {
+        use std::num::{NonZeroI64, NonZeroU32};
+
         #[derive(Deserialize, PartialEq, Debug)]
-        #[serde(tag = "id")]
-        enum Variant {
-            Combo { params: ComboParams },
+        struct Limits {
+            workers: NonZeroU32,
+            offset: Option<NonZeroI64>,
         }
 
+        let lua = Lua::new();
+        let value = lua
+            .load("return { workers = 4, offset = -1 }")
+            .eval()
+            .unwrap();
+        let limits: Limits = from_value(value).unwrap();
+        assert_eq!(4, limits.workers.get());
+        assert_eq!(Some(-1), limits.offset.map(NonZeroI64::get));
+
+        let value = lua.load("return { workers = 0 }").eval().unwrap();
+        let err = from_value::<Limits>(value).unwrap_err();
+        assert_eq!(Some("workers"), err.path());
+        assert_eq!("`workers`: value must be non-zero", err.to_string());
+
+        let value = lua
+            .load("return { workers = 1, offset = 0 }")
+            .eval()
+            .unwrap();
+        let err = from_value::<Limits>(value).unwrap_err();
+        assert_eq!("`offset`: value must be non-zero", err.to_string());
+
+        let err = from_value::<NonZeroU32>(Value::Integer(0)).unwrap_err();
+        assert!(matches!(err, crate::Error::NonZero));
+        let err = from_value::<NonZeroU32>(Value::Integer(-1)).unwrap_err();
+        assert_eq!(
+            "invalid value: integer `-1`, expected a nonzero u32",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn flatten_internally_tagged_enum() {
         #[derive(Deserialize, PartialEq, Debug)]
-        struct ComboParams {
-            values: Vec<i64>,
+        #[serde(tag = "kind")]
+        enum Body {
+            Ping,
+            Chat { from: String, text: String },
+            Move { x: i32, y: i32 },
         }
 
-        let expected = Variant::Combo {
-            params: ComboParams { values: vec![] },
-        };
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Msg {
+            id: u64,
+            #[serde(flatten)]
+            body: Body,
+        }
 
         let lua = Lua::new();
-        let result = lua
-            .load(
-                r#"
-                return {
-                    id = "Combo",
-                    params = {
-                        values = {}
-                    }
+        let value = lua
+            .load(r#"return { id = 7, kind = "Chat", from = "ada", text = "hi" }"#)
+            .eval()
+            .unwrap();
+        let msg: Msg = from_value(value).unwrap();
+        assert_eq!(
+            Msg {
+                id: 7,
+                body: Body::Chat {
+                    from: "ada".to_string(),
+                    text: "hi".to_string()
                 }
-            "#,
-            )
+            },
+            msg
+        );
+
+        let value = lua
+            .load(r#"return { x = -1, kind = "Move", y = 2, id = 8 }"#)
             .eval()
             .unwrap();
-        let result = from_value(result).unwrap();
-        assert_eq!(expected, result);
+        let msg: Msg = from_value(value).unwrap();
+        assert_eq!(8, msg.id);
+        assert_eq!(Body::Move { x: -1, y: 2 }, msg.body);
+
+        let value = lua
+            .load(r#"return { id = 9, kind = "Ping" }"#)
+            .eval()
+            .unwrap();
+        assert_eq!(Body::Ping, from_value::<Msg>(value).unwrap().body);
+
+        let value = lua
+            .load(r#"return { id = 9, kind = "Jump" }"#)
+            .eval()
+            .unwrap();
+        assert!(from_value::<Msg>(value).is_err());
     }
 
     #[test]
-    fn untagged_enum_with_empty_seq() {
-        #[derive(Deserialize, PartialEq, Debug)]
-        #[serde(untagged)]
-        enum Variant {
-            Seq { seq: Vec<u8> },
-        }
+    fn preserve_number_type() {
+        let lua = Lua::new();
+        let value: Value = lua.load("return { int = 2, float = 2.5 }").eval().unwrap();
+
+        let json: serde_json::Value = from_value(value.clone()).unwrap();
+        assert!(json["int"].is_i64());
+        assert!(json["float"].is_f64());
+        assert_eq!(r#"{"float":2.5,"int":2}"#, json.to_string());
+
+        let options = DeserializerOptions {
+            preserve_number_type: false,
+            ..Default::default()
+        };
+        let json: serde_json::Value = from_value_with(value.clone(), options.clone()).unwrap();
+        assert!(json["int"].is_f64());
+        assert!(json["float"].is_f64());
+        assert_eq!(r#"{"float":2.5,"int":2.0}"#, json.to_string());
+
+        // typed integer targets are unaffected
+        let ints: HashMap<String, i64> = from_value_with(
+            lua.load("return { a = 1 }").eval().unwrap(),
+            options.clone(),
+        )
+        .unwrap();
+        assert_eq!(Some(&1), ints.get("a"));
+        assert_eq!(
+            5i128,
+            from_value_with(Value::Integer(5), options.clone()).unwrap()
+        );
+        assert_eq!(5u128, from_value_with(Value::Integer(5), options).unwrap());
+    }
+
+    #[test]
+    fn from_value_into_reuses_vec() {
+        let lua = Lua::new();
+        let mut ports: Vec<u16> = Vec::with_capacity(16);
+        ports.push(1);
+        let ptr = ports.as_ptr();
+
+        let value = lua.load("return { 80, 443, 8080 }").eval().unwrap();
+        from_value_into(value, &mut ports).unwrap();
+        assert_eq!(vec![80, 443, 8080], ports);
+        assert_eq!(ptr, ports.as_ptr());
+        assert_eq!(16, ports.capacity());
+
+        let value = lua.load("return { 22 }").eval().unwrap();
+        from_value_into(value, &mut ports).unwrap();
+        assert_eq!(vec![22], ports);
+        assert_eq!(ptr, ports.as_ptr());
+        assert_eq!(16, ports.capacity());
+
+        let value = lua.load(r#"return { "x" }"#).eval().unwrap();
+        assert!(from_value_into(value, &mut ports).is_err());
+
+        let options = DeserializerOptions {
+            parse_string_ints: true,
+            ..Default::default()
+        };
+        let value = lua.load(r#"return { "8080" }"#).eval().unwrap();
+        from_value_into_with(value, &mut ports, options).unwrap();
+        assert_eq!(vec![8080], ports);
+    }
 
+    #[test]
+    fn index_chain_fields() {
         #[derive(Deserialize, PartialEq, Debug)]
-        struct ComboParams {
-            values: Vec<i64>,
+        struct Service {
+            name: String,
+            port: u16,
+            replicas: u8,
+            debug: bool,
         }
 
-        let expected = Variant::Seq { seq: Vec::new() };
-
         let lua = Lua::new();
-        let result = lua
+        let value: Value = lua
             .load(
                 r#"
-                return {
-                    seq = {}
-                }
-            "#,
+                local base = { name = "base", port = 80, replicas = 1, debug = false }
+                local middle = setmetatable({ port = 8080, replicas = 3 }, { __index = base })
+                return setmetatable({ name = "leaf", replicas = 5 }, { __index = middle })
+                "#,
             )
             .eval()
             .unwrap();
-        let result = from_value(result).unwrap();
-        assert_eq!(expected, result);
+
+        let options = DeserializerOptions {
+            index_chain_depth: 8,
+            ..Default::default()
+        };
+        let service: Service = from_value_with(value.clone(), options).unwrap();
+        assert_eq!(
+            Service {
+                name: "leaf".to_string(),
+                port: 8080,
+                replicas: 5,
+                debug: false,
+            },
+            service
+        );
+
+        // the depth caps how far the chain is followed
+        let options = DeserializerOptions {
+            index_chain_depth: 1,
+            ..Default::default()
+        };
+        let err = from_value_with::<Service>(value.clone(), options).unwrap_err();
+        assert_eq!("missing field `debug`", err.to_string());
+        let err = from_value::<Service>(value).unwrap_err();
+        assert_eq!("missing field `port`", err.to_string());
+
+        // cycles end at the depth cap
+        let value: Value = lua
+            .load(
+                r#"
+                local a, b = { name = "a", port = 1 }, { replicas = 2, debug = true }
+                setmetatable(a, { __index = b })
+                setmetatable(b, { __index = a })
+                return a
+                "#,
+            )
+            .eval()
+            .unwrap();
+        let options = DeserializerOptions {
+            index_chain_depth: 100,
+            ..Default::default()
+        };
+        let service: Service = from_value_with(value, options).unwrap();
+        assert_eq!(
+            ("a", 1, 2, true),
+            (
+                service.name.as_str(),
+                service.port,
+                service.replicas,
+                service.debug
+            )
+        );
+
+        // base tables are iterated like the leaf, e.g. via `__pairs`
+        let value: Value = lua
+            .load(
+                r#"
+                local defaults = { port = 80, debug = true }
+                local base = setmetatable({}, {
+                    __pairs = function() return next, defaults, nil end,
+                })
+                return setmetatable({ name = "leaf", replicas = 5 }, { __index = base })
+                "#,
+            )
+            .eval()
+            .unwrap();
+        let options = DeserializerOptions {
+            index_chain_depth: 1,
+            respect_pairs_metamethod: true,
+            ..Default::default()
+        };
+        let service: Service = from_value_with(value.clone(), options).unwrap();
+        assert_eq!((80, true), (service.port, service.debug));
+        let options = DeserializerOptions {
+            index_chain_depth: 1,
+            ..Default::default()
+        };
+        let err = from_value_with::<Service>(value, options).unwrap_err();
+        assert_eq!("missing field `port`", err.to_string());
     }
 }
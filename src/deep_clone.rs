@@ -0,0 +1,49 @@
+use crate::de::from_value;
+use crate::error::Result;
+use crate::raw::Data;
+use crate::ser::to_value;
+use mlua::{Lua, Value};
+
+/// Deep copies `value` by deserializing and serializing it again, so that the returned tables
+/// are independent of the original ones. Only works for plain data: functions, userdata and
+/// non UTF-8 strings result in an error, and metatables aren't copied.
+pub fn deep_clone<'lua>(lua: &'lua Lua, value: Value<'lua>) -> Result<Value<'lua>> {
+    let data: Data = from_value(value)?;
+    to_value(lua, data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::deep_clone;
+    use mlua::{Lua, Value};
+
+    #[test]
+    fn clone_is_independent() {
+        let lua = Lua::new();
+        let original: Value = lua
+            .load(r#"return { name = "app", ports = { 80, 443 }, tls = { enabled = true } }"#)
+            .eval()
+            .unwrap();
+        let clone = deep_clone(&lua, original.clone()).unwrap();
+        lua.globals().set("original", original).unwrap();
+        lua.globals().set("clone", clone).unwrap();
+
+        lua.load("clone.ports[1] = 8080; clone.tls.enabled = false; clone.name = nil")
+            .exec()
+            .unwrap();
+        let (name, port, enabled): (String, i64, bool) = lua
+            .load("return original.name, original.ports[1], original.tls.enabled")
+            .eval()
+            .unwrap();
+        assert_eq!(("app".to_string(), 80, true), (name, port, enabled));
+        assert_eq!(
+            (8080, 443),
+            lua.load("return clone.ports[1], clone.ports[2]")
+                .eval::<(i64, i64)>()
+                .unwrap()
+        );
+
+        let function: Value = lua.load("return { f = print }").eval().unwrap();
+        assert!(deep_clone(&lua, function).is_err());
+    }
+}
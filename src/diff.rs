@@ -0,0 +1,184 @@
+use crate::error::{key_to_string, Error, Result};
+use mlua::{Lua, Table, Value};
+
+/// A difference between two tables, see [`diff`]. Paths are the dot separated keys leading to
+/// the changed entry, e.g. `server.port`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change<'lua> {
+    Added {
+        path: String,
+        value: Value<'lua>,
+    },
+    Removed {
+        path: String,
+        value: Value<'lua>,
+    },
+    Modified {
+        path: String,
+        old: Value<'lua>,
+        new: Value<'lua>,
+    },
+}
+
+impl<'lua> Change<'lua> {
+    pub fn path(&self) -> &str {
+        match self {
+            Change::Added { path, .. } => path,
+            Change::Removed { path, .. } => path,
+            Change::Modified { path, .. } => path,
+        }
+    }
+}
+
+/// Compute the changes from table `a` to table `b`, recursing into nested tables. The changes
+/// are sorted by path. Both values must be (acyclic) tables, e.g. the output of
+/// [`to_value`](crate::to_value).
+pub fn diff<'lua>(_lua: &'lua Lua, a: Value<'lua>, b: Value<'lua>) -> Result<Vec<Change<'lua>>> {
+    match (a, b) {
+        (Value::Table(a), Value::Table(b)) => {
+            let mut changes = Vec::new();
+            diff_tables(None, a, b, &mut changes)?;
+            changes.sort_by(|a, b| a.path().cmp(b.path()));
+            Ok(changes)
+        }
+        _ => Err(Error::Message("can only diff two tables".to_string())),
+    }
+}
+
+fn diff_tables<'lua>(
+    path: Option<&str>,
+    a: Table<'lua>,
+    b: Table<'lua>,
+    changes: &mut Vec<Change<'lua>>,
+) -> Result<()> {
+    for pair in a.clone().pairs::<Value, Value>() {
+        let (key, old) = pair?;
+        let path = join_path(path, &key);
+        match (old, b.raw_get::<_, Value>(key)?) {
+            (value, Value::Nil) => changes.push(Change::Removed { path, value }),
+            (Value::Table(old), Value::Table(new)) => {
+                if old != new {
+                    diff_tables(Some(&path), old, new, changes)?;
+                }
+            }
+            (old, new) => {
+                if old != new {
+                    changes.push(Change::Modified { path, old, new });
+                }
+            }
+        }
+    }
+
+    for pair in b.pairs::<Value, Value>() {
+        let (key, value) = pair?;
+        if a.raw_get::<_, Value>(key.clone())? == Value::Nil {
+            let path = join_path(path, &key);
+            changes.push(Change::Added { path, value });
+        }
+    }
+
+    Ok(())
+}
+
+fn join_path(parent: Option<&str>, key: &Value) -> String {
+    let key = key_to_string(key);
+    match parent {
+        Some(parent) => format!("{}.{}", parent, key),
+        None => key,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{diff, Change};
+    use crate::to_value;
+    use mlua::{Lua, Value};
+    use serde::Serialize;
+
+    #[derive(Serialize)]
+    struct Server {
+        host: &'static str,
+        port: u16,
+        tls: Option<bool>,
+    }
+
+    #[derive(Serialize)]
+    struct Config {
+        name: &'static str,
+        server: Server,
+        tags: Vec<&'static str>,
+    }
+
+    #[test]
+    fn diff_configs() {
+        let lua = Lua::new();
+        let old = to_value(
+            &lua,
+            Config {
+                name: "app",
+                server: Server {
+                    host: "localhost",
+                    port: 80,
+                    tls: Some(false),
+                },
+                tags: vec!["a"],
+            },
+        )
+        .unwrap();
+        let new = to_value(
+            &lua,
+            Config {
+                name: "app",
+                server: Server {
+                    host: "localhost",
+                    port: 443,
+                    tls: None,
+                },
+                tags: vec!["a", "b"],
+            },
+        )
+        .unwrap();
+
+        let changes = diff(&lua, old, new).unwrap();
+        assert_eq!(
+            vec![
+                Change::Modified {
+                    path: "server.port".to_string(),
+                    old: Value::Integer(80),
+                    new: Value::Integer(443),
+                },
+                Change::Removed {
+                    path: "server.tls".to_string(),
+                    value: Value::Boolean(false),
+                },
+                Change::Added {
+                    path: "tags.2".to_string(),
+                    value: Value::String(lua.create_string("b").unwrap()),
+                },
+            ],
+            changes
+        );
+    }
+
+    #[test]
+    fn diff_requires_tables() {
+        let lua = Lua::new();
+        let table = lua.create_table().unwrap();
+        assert!(diff(&lua, Value::Table(table), Value::Integer(1)).is_err());
+    }
+
+    #[test]
+    fn non_string_keys_in_paths() {
+        let lua = Lua::new();
+        let old = lua
+            .load("return { flags = { [true] = 1 } }")
+            .eval()
+            .unwrap();
+        let new = lua
+            .load("return { flags = { [true] = 2 } }")
+            .eval()
+            .unwrap();
+        let changes = diff(&lua, old, new).unwrap();
+        assert_eq!("flags.true", changes[0].path());
+    }
+}
@@ -5,13 +5,75 @@ use thiserror::Error;
 pub enum Error {
     #[error("{0}")]
     Message(String),
-    // TODO: cannot be shared between threads ... other solution?
-    // #[error("Lua error: {0}")]
-    // Lua(#[from] mlua::Error),
+    #[error("missing field `{0}`")]
+    MissingField(&'static str),
+    /// A zero for a `NonZero*` integer. Prefixed with the field's path when it is a struct field.
+    #[error("value must be non-zero")]
+    NonZero,
+    /// An error returned by Lua, e.g. a `MemoryError` when a table allocation exceeds the memory
+    /// limit.
+    #[error("{0}")]
+    Lua(mlua::Error),
+    /// An error of the value at `path`, the dot separated keys leading to it (e.g.
+    /// `servers.1.port`).
+    #[error("`{path}`: {error}")]
+    WithPath { path: String, error: Box<Error> },
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+impl Error {
+    /// An error with message `msg` for the value at `path`, e.g. `servers.1.port`.
+    pub fn custom_with_path<T: std::fmt::Display>(msg: T, path: impl Into<String>) -> Self {
+        Error::WithPath {
+            path: path.into(),
+            error: Box::new(Error::Message(msg.to_string())),
+        }
+    }
+
+    /// Prepends `segment` to the path of this error, e.g. when passing it up from a nested value.
+    pub fn prepend(self, segment: impl std::fmt::Display) -> Self {
+        match self {
+            Error::WithPath { path, error } => Error::WithPath {
+                path: format!("{}.{}", segment, path),
+                error,
+            },
+            error => Error::WithPath {
+                path: segment.to_string(),
+                error: Box::new(error),
+            },
+        }
+    }
+
+    pub(crate) fn is_non_zero(&self) -> bool {
+        match self {
+            Error::NonZero => true,
+            Error::WithPath { error, .. } => error.is_non_zero(),
+            _ => false,
+        }
+    }
+
+    /// The path of the value this error is about, if known.
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            Error::WithPath { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+}
+
+/// Renders a table key for messages and paths: strings as their text, numbers and booleans as
+/// written in Lua, and other values by their type, e.g. `<table>`.
+pub(crate) fn key_to_string(key: &mlua::Value) -> String {
+    match key {
+        mlua::Value::String(s) => String::from_utf8_lossy(s.as_bytes()).into_owned(),
+        mlua::Value::Integer(i) => i.to_string(),
+        mlua::Value::Number(n) => n.to_string(),
+        mlua::Value::Boolean(b) => b.to_string(),
+        key => format!("<{}>", key.type_name()),
+    }
+}
+
 impl ser::Error for Error {
     fn custom<T: std::fmt::Display>(msg: T) -> Self {
         Error::Message(msg.to_string())
@@ -22,10 +84,91 @@ impl de::Error for Error {
     fn custom<T: std::fmt::Display>(msg: T) -> Self {
         Error::Message(msg.to_string())
     }
+
+    fn missing_field(field: &'static str) -> Self {
+        Error::MissingField(field)
+    }
+
+    // `NonZero*` types deserialize like their integer, so the only hint is serde's "a nonzero ..."
+    // expectation. The wording isn't part of serde's API, see the `serde_nonzero_wording` test.
+    fn invalid_value(unexp: de::Unexpected, exp: &dyn de::Expected) -> Self {
+        match unexp {
+            de::Unexpected::Unsigned(0) | de::Unexpected::Signed(0)
+                if exp.to_string().starts_with("a nonzero ") =>
+            {
+                Error::NonZero
+            }
+            _ => Error::Message(format!("invalid value: {}, expected {}", unexp, exp)),
+        }
+    }
 }
 
 impl From<mlua::Error> for Error {
     fn from(err: mlua::Error) -> Self {
-        Error::Message(err.to_string())
+        Error::Lua(err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Error;
+    use crate::to_value;
+    use mlua::Lua;
+    use serde::de::Error as _;
+    use serde::ser::{Serialize, SerializeMap, Serializer};
+
+    // The memory error is constructed by hand: mlua only supports memory limits with Lua
+    // 5.2 and newer, but this crate is built against Lua 5.1.
+    #[test]
+    fn preserves_memory_error() {
+        let err: Error = mlua::Error::MemoryError("not enough memory".to_string()).into();
+        match err {
+            Error::Lua(err) => assert!(matches!(err, mlua::Error::MemoryError(_))),
+            err => panic!("unexpected error: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn preserves_lua_error_from_serializer() {
+        struct NanKey;
+
+        impl Serialize for NanKey {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry(&f64::NAN, &1)?;
+                map.end()
+            }
+        }
+
+        let lua = Lua::new();
+        match to_value(&lua, NanKey).unwrap_err() {
+            Error::Lua(err) => assert!(matches!(err, mlua::Error::RuntimeError(_))),
+            err => panic!("unexpected error: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn error_paths() {
+        let err = Error::custom_with_path("expected a port", "port")
+            .prepend(1)
+            .prepend("servers");
+        assert_eq!(Some("servers.1.port"), err.path());
+        assert_eq!("`servers.1.port`: expected a port", err.to_string());
+
+        let err = Error::custom("invalid host").prepend("host");
+        assert_eq!("`host`: invalid host", err.to_string());
+        assert_eq!(None, Error::custom("no path").path());
+    }
+
+    #[test]
+    fn serde_nonzero_wording() {
+        use serde::de::value::{I64Deserializer, U32Deserializer};
+        use serde::Deserialize;
+        use std::num::{NonZeroI64, NonZeroU32};
+
+        let err = NonZeroU32::deserialize(U32Deserializer::<Error>::new(0)).unwrap_err();
+        assert!(matches!(err, Error::NonZero));
+        let err = NonZeroI64::deserialize(I64Deserializer::<Error>::new(0)).unwrap_err();
+        assert!(matches!(err, Error::NonZero));
     }
 }
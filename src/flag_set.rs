@@ -0,0 +1,126 @@
+//! Serialize and deserialize a collection of unit enum variants (e.g. a `Vec` or `HashSet` of
+//! flags) as a Lua set, i.e. a table with the variant names as keys and `true` as values, so Lua
+//! code can check `flags.Bold`.
+//!
+//! ```ignore
+//! #[derive(Serialize, Deserialize)]
+//! struct Style {
+//!     #[serde(with = "serde_mlua::flag_set")]
+//!     flags: HashSet<Flag>,
+//! }
+//! ```
+//!
+//! Entries set to `false` are skipped when deserializing.
+
+use serde::de::{MapAccess, Visitor};
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::marker::PhantomData;
+
+pub fn serialize<'a, C, T, S>(flags: &'a C, serializer: S) -> Result<S::Ok, S::Error>
+where
+    &'a C: IntoIterator<Item = &'a T>,
+    T: Serialize + 'a,
+    S: Serializer,
+{
+    let mut map = serializer.serialize_map(None)?;
+    for flag in flags {
+        map.serialize_entry(flag, &true)?;
+    }
+    map.end()
+}
+
+pub fn deserialize<'de, C, T, D>(deserializer: D) -> Result<C, D::Error>
+where
+    C: Default + Extend<T>,
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_map(FlagSetVisitor(PhantomData))
+}
+
+struct FlagSetVisitor<C, T>(PhantomData<(C, T)>);
+
+impl<'de, C, T> Visitor<'de> for FlagSetVisitor<C, T>
+where
+    C: Default + Extend<T>,
+    T: Deserialize<'de>,
+{
+    type Value = C;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a table of flags set to `true`")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<C, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut flags = C::default();
+        while let Some((flag, set)) = map.next_entry::<T, bool>()? {
+            if set {
+                flags.extend(Some(flag));
+            }
+        }
+        Ok(flags)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{from_value, to_value};
+    use mlua::{Lua, Value};
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashSet;
+
+    #[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug)]
+    enum Flag {
+        Bold,
+        Italic,
+        Underline,
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Style {
+        #[serde(with = "super")]
+        flags: HashSet<Flag>,
+        #[serde(with = "super")]
+        ordered: Vec<Flag>,
+    }
+
+    #[test]
+    fn round_trip() {
+        let lua = Lua::new();
+        let style = Style {
+            flags: vec![Flag::Bold, Flag::Underline].into_iter().collect(),
+            ordered: vec![Flag::Italic],
+        };
+        let value = to_value(&lua, &style).unwrap();
+        let flags = match &value {
+            Value::Table(t) => t.get::<_, mlua::Table>("flags").unwrap(),
+            _ => panic!("expected table"),
+        };
+        assert!(flags.get::<_, bool>("Bold").unwrap());
+        assert!(flags.get::<_, bool>("Underline").unwrap());
+        assert_eq!(Value::Nil, flags.get::<_, Value>("Italic").unwrap());
+
+        let result: Style = from_value(value).unwrap();
+        assert_eq!(style, result);
+    }
+
+    #[test]
+    fn skips_false_entries() {
+        let lua = Lua::new();
+        let value = lua
+            .load(r#"return { flags = { Bold = true, Italic = false }, ordered = {} }"#)
+            .eval()
+            .unwrap();
+        let style: Style = from_value(value).unwrap();
+        assert_eq!(
+            vec![Flag::Bold].into_iter().collect::<HashSet<_>>(),
+            style.flags
+        );
+        assert!(style.ordered.is_empty());
+    }
+}
@@ -0,0 +1,106 @@
+//! Serialize and deserialize bytes as a lowercase hex encoded Lua string (e.g. `"deadbeef"`).
+//!
+//! ```ignore
+//! #[derive(Serialize, Deserialize)]
+//! struct Key {
+//!     #[serde(with = "serde_mlua::hex")]
+//!     fingerprint: Vec<u8>,
+//! }
+//! ```
+
+use serde::{de, Deserialize, Deserializer, Serializer};
+use std::fmt::Write;
+
+pub fn serialize<S>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        write!(s, "{:02x}", b).unwrap();
+    }
+    serializer.serialize_str(&s)
+}
+
+pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    if s.len() % 2 != 0 {
+        return Err(de::Error::custom(format!(
+            "hex string has an odd length of {}",
+            s.len()
+        )));
+    }
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let digit = |b: u8| (b as char).to_digit(16);
+            digit(pair[0])
+                .zip(digit(pair[1]))
+                .map(|(hi, lo)| (hi << 4 | lo) as u8)
+                .ok_or_else(|| {
+                    de::Error::custom(format!(
+                        "invalid hex digits {:?}",
+                        String::from_utf8_lossy(pair)
+                    ))
+                })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{from_value, to_value};
+    use mlua::{Lua, Value};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Key {
+        #[serde(with = "super")]
+        fingerprint: Vec<u8>,
+    }
+
+    #[test]
+    fn round_trip() {
+        let lua = Lua::new();
+        for (bytes, hex) in [
+            (vec![0xde, 0xad, 0xbe, 0xef, 0x01], "deadbeef01"),
+            (vec![], ""),
+        ] {
+            let key = Key { fingerprint: bytes };
+            let value = to_value(&lua, &key).unwrap();
+            let encoded = match &value {
+                Value::Table(t) => t.get::<_, String>("fingerprint").unwrap(),
+                _ => panic!("expected table"),
+            };
+            assert_eq!(hex, encoded);
+            let result: Key = from_value(value).unwrap();
+            assert_eq!(key, result);
+        }
+    }
+
+    #[test]
+    fn invalid_hex() {
+        let lua = Lua::new();
+        let value = lua
+            .load(r#"return { fingerprint = "abc" }"#)
+            .eval()
+            .unwrap();
+        assert_eq!(
+            "hex string has an odd length of 3",
+            from_value::<Key>(value).unwrap_err().to_string()
+        );
+        let value = lua
+            .load(r#"return { fingerprint = "zz00" }"#)
+            .eval()
+            .unwrap();
+        assert_eq!(
+            "invalid hex digits \"zz\"",
+            from_value::<Key>(value).unwrap_err().to_string()
+        );
+        let value = lua.load(r#"return { fingerprint = "+f" }"#).eval().unwrap();
+        assert!(from_value::<Key>(value).is_err());
+    }
+}
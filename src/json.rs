@@ -0,0 +1,176 @@
+use crate::de::from_value;
+use crate::error::{Error, Result};
+use mlua::Value;
+use serde::de::{self, Deserialize, MapAccess, SeqAccess, Visitor};
+use std::fmt;
+
+/// Renders a Lua value as pretty-printed JSON, e.g. for logging. `nil` becomes `null`, array-like
+/// tables become arrays, other tables become objects with their keys as strings (e.g. `"1"` for
+/// sparse or mixed tables), and functions, userdata and threads are rejected.
+pub fn lua_value_to_json_string(value: Value) -> Result<String> {
+    let Json(json) = from_value(value)?;
+    serde_json::to_string_pretty(&json).map_err(|err| Error::Message(err.to_string()))
+}
+
+/// A `serde_json::Value` that also accepts non-string map keys, by formatting them.
+struct Json(serde_json::Value);
+
+impl<'de> Deserialize<'de> for Json {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        deserializer.deserialize_any(JsonVisitor).map(Json)
+    }
+}
+
+struct JsonVisitor;
+
+impl<'de> Visitor<'de> for JsonVisitor {
+    type Value = serde_json::Value;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("plain Lua data")
+    }
+
+    fn visit_unit<E>(self) -> std::result::Result<Self::Value, E> {
+        Ok(serde_json::Value::Null)
+    }
+
+    fn visit_bool<E>(self, v: bool) -> std::result::Result<Self::Value, E> {
+        Ok(v.into())
+    }
+
+    fn visit_i64<E>(self, v: i64) -> std::result::Result<Self::Value, E> {
+        Ok(v.into())
+    }
+
+    fn visit_u64<E>(self, v: u64) -> std::result::Result<Self::Value, E> {
+        Ok(v.into())
+    }
+
+    fn visit_f64<E>(self, v: f64) -> std::result::Result<Self::Value, E> {
+        Ok(v.into())
+    }
+
+    fn visit_str<E>(self, v: &str) -> std::result::Result<Self::Value, E> {
+        Ok(v.into())
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> std::result::Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut values = Vec::new();
+        while let Some(Json(value)) = seq.next_element()? {
+            values.push(value);
+        }
+        Ok(serde_json::Value::Array(values))
+    }
+
+    fn visit_map<A>(self, mut map: A) -> std::result::Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut object = serde_json::Map::new();
+        while let Some((JsonKey(key), Json(value))) = map.next_entry()? {
+            object.insert(key, value);
+        }
+        Ok(serde_json::Value::Object(object))
+    }
+}
+
+/// A map key, with numbers and booleans formatted as strings.
+struct JsonKey(String);
+
+impl<'de> Deserialize<'de> for JsonKey {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        deserializer.deserialize_any(JsonKeyVisitor).map(JsonKey)
+    }
+}
+
+struct JsonKeyVisitor;
+
+impl<'de> Visitor<'de> for JsonKeyVisitor {
+    type Value = String;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a string, number or boolean key")
+    }
+
+    fn visit_bool<E>(self, v: bool) -> std::result::Result<String, E> {
+        Ok(v.to_string())
+    }
+
+    fn visit_i64<E>(self, v: i64) -> std::result::Result<String, E> {
+        Ok(v.to_string())
+    }
+
+    fn visit_u64<E>(self, v: u64) -> std::result::Result<String, E> {
+        Ok(v.to_string())
+    }
+
+    fn visit_f64<E>(self, v: f64) -> std::result::Result<String, E> {
+        Ok(v.to_string())
+    }
+
+    fn visit_str<E>(self, v: &str) -> std::result::Result<String, E> {
+        Ok(v.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::lua_value_to_json_string;
+    use mlua::{Lua, Value};
+
+    #[test]
+    fn nested_table_to_json() {
+        let lua = Lua::new();
+        let value = lua
+            .load(r#"return { name = "app", ports = { 80, 443 }, tls = { enabled = true } }"#)
+            .eval()
+            .unwrap();
+        let json = lua_value_to_json_string(value).unwrap();
+        let expected = r#"{
+  "name": "app",
+  "ports": [
+    80,
+    443
+  ],
+  "tls": {
+    "enabled": true
+  }
+}"#;
+        assert_eq!(expected, json);
+    }
+
+    #[test]
+    fn nil_and_functions() {
+        let lua = Lua::new();
+        assert_eq!("null", lua_value_to_json_string(Value::Nil).unwrap());
+
+        let value = lua.load("return { f = function() end }").eval().unwrap();
+        assert!(lua_value_to_json_string(value).is_err());
+    }
+
+    #[test]
+    fn sparse_and_mixed_tables() {
+        let lua = Lua::new();
+        let cases = [
+            ("return { [1] = 1, [3] = 3 }", r#"{"1":1,"3":3}"#),
+            ("return { 1, 2, x = 3 }", r#"{"1":1,"2":2,"x":3}"#),
+            ("return { [2] = 'a' }", r#"{"2":"a"}"#),
+            ("return { [true] = 1 }", r#"{"true":1}"#),
+        ];
+        for (source, expected) in cases.iter() {
+            let value = lua.load(*source).eval().unwrap();
+            let json = lua_value_to_json_string(value).unwrap();
+            let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+            assert_eq!(*expected, json.to_string());
+        }
+    }
+}
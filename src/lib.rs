@@ -1,7 +1,43 @@
+mod check;
+mod collect;
+mod custom;
 mod de;
+mod deep_clone;
+mod diff;
 mod error;
+pub mod flag_set;
+#[cfg(feature = "hex")]
+pub mod hex;
+#[cfg(feature = "json")]
+mod json;
+mod prune;
+mod raw;
 mod ser;
+#[cfg(feature = "time")]
+pub mod time_rfc3339;
+#[cfg(feature = "time")]
+pub mod time_unix;
+#[cfg(feature = "uuid")]
+pub mod uuid_as_string;
 
-pub use de::{from_value, Deserializer};
+pub use check::can_serialize;
+pub use collect::from_value_collect_errors;
+pub use custom::{Custom, CustomSerializers};
+pub use de::{
+    classify_table, from_multi_value, from_registry_value, from_value, from_value_at,
+    from_value_into, from_value_into_with, from_value_with, validate, Deserializer,
+    DeserializerOptions, NumberStringPolicy, TableKind,
+};
+pub use deep_clone::deep_clone;
+pub use diff::{diff, Change};
 pub use error::{Error, Result};
-pub use ser::{to_value, Serializer};
+#[cfg(feature = "json")]
+pub use json::lua_value_to_json_string;
+pub use prune::prune_empty_tables;
+pub use raw::LuaRawValue;
+pub use ser::{
+    serialize_iter, serialize_one, set_serialized, set_serialized_with, to_value, to_value_with,
+    to_value_with_key_transform, to_value_with_key_transform_and_options, to_value_with_report,
+    EnumRepresentation, NonePlaceholder, SerializeReport, Serializer, SerializerOptions,
+    SharedStringCache, StructHandle, NULL,
+};
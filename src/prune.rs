@@ -0,0 +1,127 @@
+use crate::error::Result;
+use crate::ser::NULL;
+use mlua::{Lua, Table, Value};
+
+/// Recursively remove empty nested tables and [`NULL`] entries from `value`, e.g. to shrink
+/// serialized config output. Tables that only become empty by pruning are removed as well. The
+/// value must be acyclic, e.g. the output of [`to_value`](crate::to_value).
+///
+/// Entries of the array part of a table (keys `1` to `#t`) are kept, so that sequences don't get
+/// holes. Tables nested in them are still pruned.
+pub fn prune_empty_tables<'lua>(_lua: &'lua Lua, value: Value<'lua>) -> Result<Value<'lua>> {
+    if let Value::Table(table) = &value {
+        prune_table(table)?;
+    }
+    Ok(value)
+}
+
+// Prunes `table` in place, returns whether it is empty afterwards.
+fn prune_table(table: &Table) -> Result<bool> {
+    let entries = table
+        .clone()
+        .pairs::<Value, Value>()
+        .collect::<mlua::Result<Vec<_>>>()?;
+    let len = table.raw_len();
+    let mut empty = true;
+    for (key, value) in entries {
+        let remove = match &value {
+            Value::Table(nested) => prune_table(nested)?,
+            value => *value == NULL,
+        };
+        let in_array = matches!(key, Value::Integer(i) if i >= 1 && i <= len);
+        if remove && !in_array {
+            table.raw_set(key, Value::Nil)?;
+        } else {
+            empty = false;
+        }
+    }
+    Ok(empty)
+}
+
+#[cfg(test)]
+mod test {
+    use super::prune_empty_tables;
+    use crate::{to_value_with, NonePlaceholder, SerializerOptions};
+    use mlua::{Lua, Table, Value};
+    use serde::Serialize;
+    use std::collections::HashMap;
+
+    #[derive(Serialize)]
+    struct Logging {
+        level: Option<&'static str>,
+        targets: Vec<&'static str>,
+    }
+
+    #[derive(Serialize)]
+    struct Config {
+        name: &'static str,
+        logging: Logging,
+        env: HashMap<&'static str, &'static str>,
+        ports: Vec<u16>,
+    }
+
+    #[test]
+    fn prune_config() {
+        let lua = Lua::new();
+        let options = SerializerOptions {
+            none_placeholder: NonePlaceholder::Nil,
+            ..Default::default()
+        };
+        let value = to_value_with(
+            &lua,
+            Config {
+                name: "app",
+                logging: Logging {
+                    level: None,
+                    targets: vec![],
+                },
+                env: HashMap::new(),
+                ports: vec![80],
+            },
+            options,
+        )
+        .unwrap();
+
+        let table = match prune_empty_tables(&lua, value).unwrap() {
+            Value::Table(t) => t,
+            _ => panic!("expected table"),
+        };
+        let mut keys: Vec<String> = table
+            .clone()
+            .pairs::<String, Value>()
+            .map(|pair| pair.unwrap().0)
+            .collect();
+        keys.sort();
+        assert_eq!(vec!["name", "ports"], keys);
+        assert_eq!(vec![80], table.get::<_, Vec<u16>>("ports").unwrap());
+    }
+
+    #[test]
+    fn keeps_non_tables() {
+        let lua = Lua::new();
+        assert_eq!(
+            Value::Integer(1),
+            prune_empty_tables(&lua, Value::Integer(1)).unwrap()
+        );
+        let empty: Table = lua.create_table().unwrap();
+        assert!(prune_empty_tables(&lua, Value::Table(empty)).is_ok());
+    }
+
+    #[test]
+    fn keeps_sequence_entries() {
+        let lua = Lua::new();
+        let layers: Vec<HashMap<&str, Vec<u8>>> = vec![
+            HashMap::new(),
+            vec![("a", vec![1]), ("b", vec![])].into_iter().collect(),
+        ];
+        let value = to_value_with(&lua, &layers, SerializerOptions::default()).unwrap();
+        let table = match prune_empty_tables(&lua, value).unwrap() {
+            Value::Table(t) => t,
+            _ => panic!("expected table"),
+        };
+        assert_eq!(2, table.len().unwrap());
+        let layers: Vec<HashMap<String, Vec<u8>>> = crate::from_value(Value::Table(table)).unwrap();
+        assert!(layers[0].is_empty());
+        assert_eq!(1, layers[1].len());
+    }
+}
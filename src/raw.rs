@@ -0,0 +1,312 @@
+use crate::error::{Error, Result};
+use crate::ser::to_value;
+use mlua::{Lua, Value};
+use serde::de::value::SeqDeserializer;
+use serde::de::value::{BorrowedStrDeserializer, MapAccessDeserializer, MapDeserializer};
+use serde::de::{self, Deserialize, DeserializeOwned, IntoDeserializer, MapAccess, SeqAccess};
+use serde::de::{Unexpected, Visitor};
+use serde::ser::{Serialize, SerializeMap, SerializeSeq, Serializer};
+use std::fmt;
+
+/// A value captured as-is during deserialization, to be deserialized later on demand (similar
+/// to `serde_json::value::RawValue`).
+///
+/// The value is kept as an owned copy of the plain data it consists of, taken with the options
+/// of the deserializer it was read from. Functions, userdata and non UTF-8 strings can therefore
+/// not be captured.
+///
+/// ```
+/// use mlua::Lua;
+/// use serde::Deserialize;
+/// use serde_mlua::{from_value, LuaRawValue};
+/// use std::collections::HashMap;
+///
+/// #[derive(Deserialize)]
+/// struct Config {
+///     name: String,
+///     plugins: LuaRawValue,
+/// }
+///
+/// let lua = Lua::new();
+/// let value = lua.load(r#"return { name = "app", plugins = { lint = 1 } }"#).eval().unwrap();
+/// let config: Config = from_value(value).unwrap();
+/// let plugins: HashMap<String, u8> = config.plugins.deserialize_into().unwrap();
+/// assert_eq!(Some(&1), plugins.get("lint"));
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct LuaRawValue {
+    data: Data,
+}
+
+impl LuaRawValue {
+    /// Deserializes the captured value.
+    pub fn deserialize_into<T>(&self) -> Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        T::deserialize(&self.data)
+    }
+
+    /// Converts the captured value back into a Lua value.
+    pub fn to_value<'lua>(&self, lua: &'lua Lua) -> Result<Value<'lua>> {
+        to_value(lua, &self.data)
+    }
+}
+
+impl<'de> Deserialize<'de> for LuaRawValue {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        Data::deserialize(deserializer).map(|data| LuaRawValue { data })
+    }
+}
+
+impl Serialize for LuaRawValue {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.data.serialize(serializer)
+    }
+}
+
+/// An owned copy of a Lua value.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Data {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Seq(Vec<Data>),
+    Map(Vec<(Data, Data)>),
+}
+
+impl<'de> Deserialize<'de> for Data {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        deserializer.deserialize_any(DataVisitor)
+    }
+}
+
+struct DataVisitor;
+
+impl<'de> Visitor<'de> for DataVisitor {
+    type Value = Data;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("plain Lua data")
+    }
+
+    fn visit_unit<E>(self) -> std::result::Result<Data, E> {
+        Ok(Data::Nil)
+    }
+
+    fn visit_bool<E>(self, v: bool) -> std::result::Result<Data, E> {
+        Ok(Data::Bool(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> std::result::Result<Data, E> {
+        Ok(Data::Int(v))
+    }
+
+    fn visit_f64<E>(self, v: f64) -> std::result::Result<Data, E> {
+        Ok(Data::Float(v))
+    }
+
+    fn visit_str<E>(self, v: &str) -> std::result::Result<Data, E> {
+        Ok(Data::Str(v.to_string()))
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> std::result::Result<Data, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut values = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(value) = seq.next_element()? {
+            values.push(value);
+        }
+        Ok(Data::Seq(values))
+    }
+
+    fn visit_map<A>(self, mut map: A) -> std::result::Result<Data, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut entries = Vec::with_capacity(map.size_hint().unwrap_or(0));
+        while let Some(entry) = map.next_entry()? {
+            entries.push(entry);
+        }
+        Ok(Data::Map(entries))
+    }
+}
+
+impl Serialize for Data {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            Data::Nil => serializer.serialize_unit(),
+            Data::Bool(v) => serializer.serialize_bool(*v),
+            Data::Int(v) => serializer.serialize_i64(*v),
+            Data::Float(v) => serializer.serialize_f64(*v),
+            Data::Str(v) => serializer.serialize_str(v),
+            Data::Seq(values) => {
+                let mut seq = serializer.serialize_seq(Some(values.len()))?;
+                for value in values {
+                    seq.serialize_element(value)?;
+                }
+                seq.end()
+            }
+            Data::Map(entries) => {
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (key, value) in entries {
+                    map.serialize_entry(key, value)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &'de Data {
+    type Error = Error;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        match self {
+            Data::Nil => visitor.visit_unit(),
+            Data::Bool(v) => visitor.visit_bool(*v),
+            Data::Int(v) => visitor.visit_i64(*v),
+            Data::Float(v) => visitor.visit_f64(*v),
+            Data::Str(v) => visitor.visit_borrowed_str(v),
+            Data::Seq(values) => {
+                let mut deserializer = SeqDeserializer::new(values.iter());
+                let seq = visitor.visit_seq(&mut deserializer)?;
+                deserializer.end()?;
+                Ok(seq)
+            }
+            Data::Map(entries) => {
+                let mut deserializer = MapDeserializer::new(entries.iter().map(|(k, v)| (k, v)));
+                let map = visitor.visit_map(&mut deserializer)?;
+                deserializer.end()?;
+                Ok(map)
+            }
+        }
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        match self {
+            Data::Nil => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        match self {
+            Data::Str(v) => visitor.visit_enum(BorrowedStrDeserializer::new(v)),
+            Data::Map(entries) if entries.len() == 1 => {
+                let entries = entries.iter().map(|(k, v)| (k, v));
+                visitor.visit_enum(MapAccessDeserializer::new(MapDeserializer::new(entries)))
+            }
+            _ => Err(de::Error::invalid_type(
+                Unexpected::Other("non-enum value"),
+                &"a string or a table with a single key",
+            )),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        unit unit_struct newtype_struct seq tuple tuple_struct map struct identifier
+        ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, Error> for &'de Data {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LuaRawValue;
+    use crate::{from_value, from_value_with, DeserializerOptions};
+    use mlua::{Lua, Value};
+    use serde::Deserialize;
+    use std::collections::HashMap;
+
+    #[derive(Deserialize)]
+    struct Config {
+        name: String,
+        plugins: LuaRawValue,
+    }
+
+    #[test]
+    fn deserialize_lazily() {
+        let lua = Lua::new();
+        let value = lua
+            .load(r#"return { name = "app", plugins = { lint = 1, fmt = 2 } }"#)
+            .eval()
+            .unwrap();
+        let config: Config = from_value(value).unwrap();
+        assert_eq!("app", config.name);
+
+        let plugins: HashMap<String, u8> = config.plugins.deserialize_into().unwrap();
+        let mut expected = HashMap::new();
+        expected.insert("lint".to_string(), 1);
+        expected.insert("fmt".to_string(), 2);
+        assert_eq!(expected, plugins);
+
+        let plugins = config.plugins.to_value(&lua).unwrap();
+        lua.globals().set("plugins", plugins).unwrap();
+        assert_eq!(2, lua.load("return plugins.fmt").eval::<i64>().unwrap());
+    }
+
+    #[test]
+    fn captures_with_deserializer_options() {
+        let lua = Lua::new();
+        let value = lua
+            .load(r#"return { name = "app", plugins = { lint = "null", fmt = 2 } }"#)
+            .eval()
+            .unwrap();
+        let options = DeserializerOptions {
+            null_sentinels: vec![Value::String(lua.create_string("null").unwrap())],
+            ..Default::default()
+        };
+        let config: Config = from_value_with(value, options).unwrap();
+        let plugins: HashMap<String, Option<u8>> = config.plugins.deserialize_into().unwrap();
+        assert_eq!(Some(&None), plugins.get("lint"));
+        assert_eq!(Some(&Some(2)), plugins.get("fmt"));
+    }
+
+    #[test]
+    fn rejects_functions() {
+        let lua = Lua::new();
+        let value: Value = lua
+            .load(r#"return { name = "app", plugins = { lint = print } }"#)
+            .eval()
+            .unwrap();
+        assert!(from_value::<Config>(value).is_err());
+    }
+}
@@ -1,43 +1,717 @@
 // based on https://github.com/wez/wezterm/tree/master/src/scripting/serde_lua
 
-use crate::error::{Error, Result};
-use mlua::{Lua, Table, ToLua, Value};
+use crate::custom::{self, CustomSerializers};
+use crate::error::{key_to_string, Error, Result};
+use mlua::{AnyUserData, Function, LightUserData, Lua, RegistryKey, Table, ToLua, UserData, Value};
 use serde::{ser, Serialize};
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// A sentinel (a `NULL` light userdata) that represents an explicit `nil` where Lua can't store
+/// one, e.g. as a table value.
+pub const NULL: Value<'static> = Value::LightUserData(LightUserData(std::ptr::null_mut()));
+
+/// Options to customize how Rust values are serialized into Lua values.
+#[derive(Clone, Debug)]
+pub struct SerializerOptions {
+    /// How to write struct fields and map values that serialized to `nil` (e.g. `None`, or a
+    /// `serde_json::Value::Null`).
+    pub none_placeholder: NonePlaceholder,
+    /// Error if a map or struct writes the same key twice, e.g. if a `#[serde(flatten)]` map
+    /// contains a key that shadows a named field.
+    pub error_on_duplicate_keys: bool,
+    /// How enum variants with data are represented.
+    pub enum_representation: EnumRepresentation,
+    /// Verify that produced tables carry no metatables, so the output is plain data.
+    pub plain_data_only: bool,
+    /// Write sequences and tuple variants as 0-based arrays, i.e. starting at index `0` instead
+    /// of `1`.
+    pub zero_based_arrays: bool,
+    /// Reuse a single Lua string for repeated Rust strings within one serialization, instead of
+    /// creating a new Lua string each time.
+    pub intern_strings: bool,
+    /// Only allow enum map keys that serialize to their variant name (unit variants), and error
+    /// for variants that carry data instead of producing table keys.
+    pub enum_keys_as_string: bool,
+    /// Write integer, float and boolean map keys as strings (e.g. `"1"`, `"1.5"`, `"true"`), for
+    /// tables that are passed on to JSON encoders that only accept string keys.
+    pub stringify_map_keys: bool,
+    /// Write booleans as the integers `1` and `0`.
+    pub bool_as_int: bool,
+    /// Error if sequences, maps and structs are nested deeper than this, e.g. to guard against
+    /// cyclic data structures.
+    pub max_depth: Option<usize>,
+    /// Error if a float doesn't survive the round trip through a Lua number unchanged, e.g. on
+    /// Lua builds that use 32-bit floats.
+    pub strict_float_precision: bool,
+    /// Insert map entries sorted by key, so that the resulting tables (e.g. the order `pairs`
+    /// visits their keys in) don't depend on `HashMap` iteration order.
+    pub deterministic: bool,
+    /// Reuse a single Lua table for structurally equal tables stored in a sequence, map or struct
+    /// (e.g. repeated elements of a `Vec`), to save memory. Tables with metatables aren't shared.
+    ///
+    /// Every stored table is compared by content, which makes serialization slower. The shared
+    /// tables are the same Lua table, so modifying one of them in Lua modifies all the values
+    /// that were deduplicated into it.
+    pub dedup_equal_values: bool,
+    /// Write `None` sequence and tuple variant elements as the [`NULL`] sentinel instead of `nil`, so that the
+    /// array stays contiguous. Add [`NULL`] to
+    /// [`DeserializerOptions::null_sentinels`](crate::DeserializerOptions::null_sentinels) to
+    /// read them back as `None`.
+    pub preserve_seq_holes: bool,
+    /// Error if the integer keys of a map aren't contiguous from `1` (or `0` with
+    /// `zero_based_arrays`), as such sparse arrays break `ipairs` and the length operator.
+    pub reject_sparse_int_keys: bool,
+    /// Names of structs (as passed to `serialize_struct`, i.e. the Rust type name unless renamed)
+    /// that are serialized into an opaque [`StructHandle`] userdata instead of a table when
+    /// nested in another value, so that Lua scripts can't read or modify them.
+    pub structs_as_userdata: HashSet<String>,
+    /// Added to integer map keys, e.g. `1` to write the keys of a 0-based `HashMap<u32, T>` as
+    /// 1-based Lua keys. Set the same
+    /// [`DeserializerOptions::int_key_offset`](crate::DeserializerOptions::int_key_offset) to
+    /// subtract it again.
+    pub int_key_offset: i64,
+    /// The value `is_human_readable` reports, `true` by default. Set it to `false` so that types
+    /// like `IpAddr` use their compact representation (e.g. `{ V4 = { 127, 0, 0, 1 } }` instead
+    /// of `"127.0.0.1"`).
+    pub human_readable: bool,
+    /// Reuse Lua strings across serializations, e.g. for enum labels that recur in many calls.
+    /// Takes precedence over `intern_strings`.
+    pub string_cache: Option<SharedStringCache>,
+    /// Write empty strings as `nil`, for Lua code that doesn't distinguish the two. Map keys and
+    /// sequence elements are left as is, as `nil` would drop the key or leave a hole. Set
+    /// [`DeserializerOptions::nil_as_empty_string`](crate::DeserializerOptions::nil_as_empty_string)
+    /// to read them back.
+    pub empty_string_as_nil: bool,
+    /// Serializers for values wrapped in [`Custom`](crate::Custom), see
+    /// [`register_custom`](Self::register_custom).
+    pub custom: CustomSerializers,
+}
+
+impl Default for SerializerOptions {
+    fn default() -> Self {
+        SerializerOptions {
+            none_placeholder: Default::default(),
+            error_on_duplicate_keys: false,
+            enum_representation: Default::default(),
+            plain_data_only: false,
+            zero_based_arrays: false,
+            intern_strings: false,
+            enum_keys_as_string: false,
+            stringify_map_keys: false,
+            bool_as_int: false,
+            max_depth: None,
+            strict_float_precision: false,
+            deterministic: false,
+            dedup_equal_values: false,
+            preserve_seq_holes: false,
+            reject_sparse_int_keys: false,
+            structs_as_userdata: Default::default(),
+            int_key_offset: 0,
+            human_readable: true,
+            string_cache: None,
+            empty_string_as_nil: false,
+            custom: Default::default(),
+        }
+    }
+}
+
+impl SerializerOptions {
+    /// Serializes `T` with `f` instead of its `Serialize` impl wherever it is wrapped in a
+    /// [`Custom`](crate::Custom). Replaces a serializer registered for `T` before.
+    pub fn register_custom<T, F>(&mut self, f: F)
+    where
+        T: 'static,
+        F: for<'lua> Fn(&T, &'lua Lua) -> Result<Value<'lua>> + Send + Sync + 'static,
+    {
+        self.custom.insert(f);
+    }
+}
+
+/// How struct fields and map values that serialized to `nil` are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NonePlaceholder {
+    /// Omit the field or entry (Lua tables can't store `nil` values).
+    #[default]
+    Omit,
+    /// Keep the field or entry, set to the [`NULL`] sentinel. Together with [`NULL`] in
+    /// [`DeserializerOptions::null_sentinels`](crate::DeserializerOptions::null_sentinels), e.g.
+    /// JSON nulls survive a round trip through Lua.
+    Nil,
+    /// Keep the field or entry, set to `false`.
+    False,
+}
+
+/// How enum variants with data are represented as Lua tables. Unit variants are always
+/// represented by their name.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum EnumRepresentation {
+    /// `{ Variant = content }`
+    #[default]
+    ExternallyTagged,
+    /// `{ [tag] = "Variant", ...content }`, where the content of newtype, tuple and struct
+    /// variants must be a table. The tag is looked up by key, so it doesn't matter whether it
+    /// was set before or after the content.
+    InternallyTagged { tag: String },
+    /// `{ [name_key] = "Variant", [index_key] = index, ...content }`, like `InternallyTagged`,
+    /// but also with the index of the variant (starting at `0`), e.g. for dispatch tables in Lua.
+    /// Deserialization uses the name, or the index if the name is missing.
+    Tagged { name_key: String, index_key: String },
+}
+
+/// An opaque userdata wrapping a serialized struct, see
+/// [`SerializerOptions::structs_as_userdata`]. Lua can't index it, Rust code can access the
+/// struct's table via [`StructHandle::table`].
+pub struct StructHandle {
+    name: &'static str,
+}
+
+impl StructHandle {
+    /// The name of the wrapped struct.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The table the struct was serialized into, stored as the user value of the `userdata`.
+    pub fn table<'lua>(userdata: &AnyUserData<'lua>) -> Result<Table<'lua>> {
+        Ok(userdata.get_user_value()?)
+    }
+}
+
+impl UserData for StructHandle {}
+
+/// A cache of Lua strings that outlives a single serialization, see
+/// [`SerializerOptions::string_cache`]. Cloning it is cheap and shares the cache.
+///
+/// The strings are kept alive in the Lua registry until the cache is dropped, so it should only
+/// be used for a bounded set of strings.
+#[derive(Clone, Debug, Default)]
+pub struct SharedStringCache {
+    strings: Arc<Mutex<HashMap<String, RegistryKey>>>,
+}
+
+impl SharedStringCache {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The number of cached strings.
+    pub fn len(&self) -> usize {
+        self.strings().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings().is_empty()
+    }
+
+    fn strings(&self) -> MutexGuard<'_, HashMap<String, RegistryKey>> {
+        // the map stays consistent even if a panic poisoned the lock
+        self.strings.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn get<'lua>(&self, lua: &'lua Lua, s: &str) -> Result<mlua::String<'lua>> {
+        let mut strings = self.strings();
+        if let Some(key) = strings.get(s) {
+            // strings created by another Lua instance are replaced
+            if lua.owns_registry_value(key) {
+                return Ok(lua.registry_value(key)?);
+            }
+        }
+        let string = lua.create_string(s)?;
+        strings.insert(s.to_string(), lua.create_registry_value(string.clone())?);
+        Ok(string)
+    }
+}
+
+/// What was lost or changed while serializing, see [`to_value_with_report`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SerializeReport {
+    /// Map and struct keys that were dropped because their value serialized to `nil`.
+    pub dropped_keys: Vec<String>,
+    /// Integers that didn't fit into a Lua integer and were coerced, either to a float (`u64`)
+    /// or to a decimal string (`i128`/`u128`).
+    pub large_int_coercions: Vec<String>,
+}
 
 pub fn to_value<'lua, T>(lua: &'lua Lua, input: T) -> Result<Value<'lua>>
 where
     T: Serialize,
 {
-    input.serialize(Serializer { lua })
+    to_value_with(lua, input, SerializerOptions::default())
+}
+
+pub fn to_value_with<'lua, T>(
+    lua: &'lua Lua,
+    input: T,
+    options: SerializerOptions,
+) -> Result<Value<'lua>>
+where
+    T: Serialize,
+{
+    serialize(lua, input, Rc::new(options), None).map(|(value, _)| value)
 }
 
+/// Serializes a single value with shared `options`, e.g. from an adapter that holds on to the
+/// options and serializes values one at a time, without copying the options for every value:
+///
+/// ```
+/// use mlua::{Lua, ToLua, Value};
+/// use serde::Serialize;
+/// use serde_mlua::{serialize_one, SerializerOptions};
+/// use std::rc::Rc;
+///
+/// /// Converts any `Serialize` value into a Lua value with the given options.
+/// struct AsLua<'a, T>(T, &'a Rc<SerializerOptions>);
+///
+/// impl<'lua, 'a, T: Serialize> ToLua<'lua> for AsLua<'a, T> {
+///     fn to_lua(self, lua: &'lua Lua) -> mlua::Result<Value<'lua>> {
+///         serialize_one(lua, &self.0, self.1).map_err(mlua::Error::external)
+///     }
+/// }
+///
+/// let lua = Lua::new();
+/// let options = Rc::new(SerializerOptions {
+///     bool_as_int: true,
+///     ..Default::default()
+/// });
+/// lua.globals().set("enabled", AsLua(true, &options)).unwrap();
+/// assert_eq!(1, lua.load("return enabled").eval::<i64>().unwrap());
+/// ```
+pub fn serialize_one<'lua, T>(
+    lua: &'lua Lua,
+    value: &T,
+    options: &Rc<SerializerOptions>,
+) -> Result<Value<'lua>>
+where
+    T: ?Sized + Serialize,
+{
+    serialize(lua, value, options.clone(), None).map(|(value, _)| value)
+}
+
+/// Serializes the items of `iter` into a sequence table one at a time, without collecting them
+/// into a `Vec` first.
+pub fn serialize_iter<'lua, I>(lua: &'lua Lua, iter: I) -> Result<Value<'lua>>
+where
+    I: IntoIterator,
+    I::Item: Serialize,
+{
+    let iter = IterSeq(RefCell::new(Some(iter.into_iter())));
+    to_value(lua, iter)
+}
+
+/// Serializes the wrapped iterator as a sequence, once.
+struct IterSeq<I>(RefCell<Option<I>>);
+
+impl<I> Serialize for IterSeq<I>
+where
+    I: Iterator,
+    I::Item: Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        match self.0.borrow_mut().take() {
+            Some(iter) => serializer.collect_seq(iter),
+            None => Err(ser::Error::custom("iterator was already serialized")),
+        }
+    }
+}
+
+/// Serializes `value` and sets it as `table[key]`, short for
+/// `table.set(key, to_value(lua, value)?)`.
+pub fn set_serialized<'lua, K, T>(
+    lua: &'lua Lua,
+    table: &Table<'lua>,
+    key: K,
+    value: &T,
+) -> Result<()>
+where
+    K: ToLua<'lua>,
+    T: ?Sized + Serialize,
+{
+    set_serialized_with(lua, table, key, value, &Default::default())
+}
+
+/// Like [`set_serialized`], but with shared `options`, see [`serialize_one`].
+pub fn set_serialized_with<'lua, K, T>(
+    lua: &'lua Lua,
+    table: &Table<'lua>,
+    key: K,
+    value: &T,
+    options: &Rc<SerializerOptions>,
+) -> Result<()>
+where
+    K: ToLua<'lua>,
+    T: ?Sized + Serialize,
+{
+    let value = serialize_one(lua, value, options)?;
+    table.set(key, value)?;
+    Ok(())
+}
+
+/// Like [`to_value_with`], but also returns a [`SerializeReport`] listing lossy conversions.
+pub fn to_value_with_report<'lua, T>(
+    lua: &'lua Lua,
+    input: T,
+    options: SerializerOptions,
+) -> Result<(Value<'lua>, SerializeReport)>
+where
+    T: Serialize,
+{
+    serialize(lua, input, Rc::new(options), None)
+}
+
+/// Like [`to_value`], but passes every struct field name and string map key through `transform`,
+/// e.g. to convert between naming conventions. Map values are left as they are.
+pub fn to_value_with_key_transform<'lua, T, F>(
+    lua: &'lua Lua,
+    input: T,
+    transform: F,
+) -> Result<Value<'lua>>
+where
+    T: Serialize,
+    F: Fn(&str) -> String + 'lua,
+{
+    to_value_with_key_transform_and_options(lua, input, transform, Default::default())
+}
+
+/// Like [`to_value_with_key_transform`], but with `options`.
+pub fn to_value_with_key_transform_and_options<'lua, T, F>(
+    lua: &'lua Lua,
+    input: T,
+    transform: F,
+    options: SerializerOptions,
+) -> Result<Value<'lua>>
+where
+    T: Serialize,
+    F: Fn(&str) -> String + 'lua,
+{
+    let options = Rc::new(options);
+    serialize(lua, input, options, Some(Rc::new(transform))).map(|(value, _)| value)
+}
+
+type KeyTransform<'lua> = Rc<dyn Fn(&str) -> String + 'lua>;
+
+fn serialize<'lua, T>(
+    lua: &'lua Lua,
+    input: T,
+    options: Rc<SerializerOptions>,
+    key_transform: Option<KeyTransform<'lua>>,
+) -> Result<(Value<'lua>, SerializeReport)>
+where
+    T: Serialize,
+{
+    let report = Rc::new(RefCell::new(SerializeReport::default()));
+    let value = input.serialize(Serializer {
+        lua,
+        options,
+        report: report.clone(),
+        interned: Default::default(),
+        field_keys: Default::default(),
+        struct_tables: Default::default(),
+        dedup: Default::default(),
+        buffer: Default::default(),
+        float_scratch: Default::default(),
+        key_transform,
+        map_key: false,
+        in_seq: false,
+        depth: 0,
+    })?;
+    let report = report.borrow().clone();
+    Ok((value, report))
+}
+
+#[derive(Clone)]
 pub struct Serializer<'lua> {
     lua: &'lua Lua,
+    options: Rc<SerializerOptions>,
+    report: Rc<RefCell<SerializeReport>>,
+    interned: Rc<RefCell<HashMap<String, mlua::String<'lua>>>>,
+    /// Struct field names, created once per serialization and reused for every struct.
+    field_keys: Rc<RefCell<HashMap<&'static str, mlua::String<'lua>>>>,
+    /// Functions creating a table pre-sized for the fields of a struct, by struct name.
+    struct_tables: Rc<RefCell<HashMap<&'static str, Function<'lua>>>>,
+    /// Tables stored so far, by their content, see `dedup_equal_values`.
+    dedup: Rc<RefCell<Dedup<'lua>>>,
+    /// Scratch buffer for `collect_str`, reused across calls.
+    buffer: Rc<RefCell<String>>,
+    /// Scratch table to read floats back from Lua for `strict_float_precision`, created on first
+    /// use and reused across calls.
+    float_scratch: Rc<RefCell<Option<Table<'lua>>>>,
+    /// Applied to field names and string map keys, see [`to_value_with_key_transform`].
+    key_transform: Option<KeyTransform<'lua>>,
+    /// Whether a map key is being serialized.
+    map_key: bool,
+    /// Whether a sequence or tuple variant element is being serialized.
+    in_seq: bool,
+    /// How many sequences, maps and structs enclose the value being serialized.
+    depth: usize,
 }
 
 pub struct SeqSerializer<'lua> {
-    lua: &'lua Lua,
+    ser: Serializer<'lua>,
     table: Table<'lua>,
     index: usize,
 }
 
 pub struct TupleVariantSerializer<'lua> {
-    lua: &'lua Lua,
+    ser: Serializer<'lua>,
     table: Table<'lua>,
     index: usize,
     name: String,
+    variant_index: u32,
 }
 
 pub struct MapSerializer<'lua> {
-    lua: &'lua Lua,
+    ser: Serializer<'lua>,
     table: Table<'lua>,
     key: Option<Value<'lua>>,
+    /// The entries of a map, buffered to be sorted if `deterministic` is set.
+    entries: Option<Vec<(Value<'lua>, Value<'lua>)>>,
+    /// The struct name, if the struct is wrapped in a [`StructHandle`].
+    handle: Option<&'static str>,
+    /// The struct name and the keys of its fields, if there is no pre-sized table for the struct
+    /// yet.
+    fields: Option<(&'static str, Vec<Value<'lua>>)>,
 }
 
 pub struct StructVariantSerializer<'lua> {
-    lua: &'lua Lua,
+    ser: Serializer<'lua>,
     table: Table<'lua>,
     name: String,
+    variant_index: u32,
+}
+
+impl<'lua> Serializer<'lua> {
+    fn finish(&self, table: Table<'lua>) -> Result<Value<'lua>> {
+        if self.options.plain_data_only && table.get_metatable().is_some() {
+            return Err(Error::Message(
+                "produced table has a metatable, but plain_data_only is set".to_string(),
+            ));
+        }
+        Ok(Value::Table(table))
+    }
+
+    /// The value stored for the struct field `key`, or `None` if `none_placeholder` omits it, in
+    /// which case the key is reported as dropped.
+    fn struct_field_value(&self, key: &str, value: Value<'lua>) -> Option<Value<'lua>> {
+        match value {
+            Value::Nil => match self.options.none_placeholder {
+                NonePlaceholder::Omit => {
+                    let key = key.to_string();
+                    self.report.borrow_mut().dropped_keys.push(key);
+                    None
+                }
+                NonePlaceholder::Nil => Some(NULL),
+                NonePlaceholder::False => Some(Value::Boolean(false)),
+            },
+            value => Some(value),
+        }
+    }
+
+    /// The value stored for a sequence or tuple variant element, see `preserve_seq_holes`.
+    fn seq_element(&self, value: Value<'lua>) -> Result<Value<'lua>> {
+        match value {
+            Value::Nil if self.options.preserve_seq_holes => Ok(NULL),
+            value => self.dedup(value),
+        }
+    }
+
+    // The serializer for the elements of a new sequence, map or struct.
+    fn nested(self) -> Result<Self> {
+        let depth = nested_depth(self.depth, &self.options)?;
+        Ok(Serializer {
+            depth,
+            in_seq: false,
+            ..self
+        })
+    }
+
+    fn key_serializer(&self) -> Self {
+        Serializer {
+            map_key: true,
+            ..self.clone()
+        }
+    }
+
+    fn check_variant_key(&self, variant: &str) -> Result<()> {
+        check_variant_key(variant, self.map_key, &self.options)
+    }
+
+    // Adds the tag(s) of a variant to its content, unless it is externally tagged.
+    fn set_variant_tags(
+        &self,
+        table: &Table<'lua>,
+        variant: &str,
+        variant_index: u32,
+    ) -> Result<()> {
+        let set_tag = |key: &str, value: Value<'lua>| -> Result<()> {
+            if table.raw_get::<_, Value>(key)? != Value::Nil {
+                return Err(Error::Message(format!(
+                    "cannot tag variant `{}`, its content already has a key `{}`",
+                    variant, key
+                )));
+            }
+            Ok(table.raw_set(key, value)?)
+        };
+        match &self.options.enum_representation {
+            EnumRepresentation::ExternallyTagged => {}
+            EnumRepresentation::InternallyTagged { tag } => {
+                set_tag(tag, Value::String(self.lua.create_string(variant)?))?
+            }
+            EnumRepresentation::Tagged {
+                name_key,
+                index_key,
+            } => {
+                set_tag(name_key, Value::String(self.lua.create_string(variant)?))?;
+                set_tag(index_key, Value::Integer(variant_index.into()))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn field_key(&self, key: &'static str) -> Result<Value<'lua>> {
+        let mut field_keys = self.field_keys.borrow_mut();
+        if let Some(s) = field_keys.get(key) {
+            return Ok(Value::String(s.clone()));
+        }
+        let s = match &self.key_transform {
+            Some(transform) => self.lua.create_string(&transform(key))?,
+            None => self.lua.create_string(key)?,
+        };
+        field_keys.insert(key, s.clone());
+        Ok(Value::String(s))
+    }
+
+    // Returns the table stored earlier that is equal to `value`, if `dedup_equal_values` is set.
+    fn dedup(&self, value: Value<'lua>) -> Result<Value<'lua>> {
+        let table = match value {
+            Value::Table(table) if self.options.dedup_equal_values => table,
+            value => return Ok(value),
+        };
+        let mut dedup = self.dedup.borrow_mut();
+        match dedup.id(self.lua, &table)? {
+            Some(id) => Ok(Value::Table(dedup.tables[id].clone())),
+            None => Ok(Value::Table(table)),
+        }
+    }
+
+    fn report_coercion(&self, v: impl ToString) {
+        self.report
+            .borrow_mut()
+            .large_int_coercions
+            .push(v.to_string());
+    }
+}
+
+fn check_float_precision(v: f64, back: f64) -> Result<()> {
+    if v == back || (v.is_nan() && back.is_nan()) {
+        Ok(())
+    } else {
+        Err(Error::Message(format!(
+            "float {} lost precision as a Lua number (became {})",
+            v, back
+        )))
+    }
+}
+
+/// The tables stored so far, see `dedup_equal_values`.
+#[derive(Default)]
+struct Dedup<'lua> {
+    /// Indices into `tables`, by the content of the table with nested tables written as their
+    /// index.
+    by_content: HashMap<Vec<u8>, usize>,
+    tables: Vec<Table<'lua>>,
+    /// The index of every table looked at so far, or `false` if it can't be compared by content.
+    ids: Option<Table<'lua>>,
+}
+
+impl<'lua> Dedup<'lua> {
+    // Returns the index of the stored table equal to `table`. Nested tables are looked up first
+    // and remembered, so every table is only fingerprinted once.
+    fn id(&mut self, lua: &'lua Lua, table: &Table<'lua>) -> Result<Option<usize>> {
+        let ids = match &self.ids {
+            Some(ids) => ids.clone(),
+            None => self.ids.get_or_insert(lua.create_table()?).clone(),
+        };
+        match ids.raw_get::<_, Value>(table.clone())? {
+            Value::Integer(id) => return Ok(Some(id as usize)),
+            Value::Boolean(false) => return Ok(None),
+            _ => {}
+        }
+        // also stops at cycles
+        ids.raw_set(table.clone(), false)?;
+        if table.get_metatable().is_some() {
+            return Ok(None);
+        }
+
+        let mut entries = table
+            .clone()
+            .pairs::<Value, Value>()
+            .collect::<mlua::Result<Vec<_>>>()?;
+        entries.sort_by(|(a, _), (b, _)| compare_keys(a, b));
+        let mut content = Vec::new();
+        for (key, value) in &entries {
+            if !self.fingerprint(lua, key, &mut content)?
+                || !self.fingerprint(lua, value, &mut content)?
+            {
+                return Ok(None);
+            }
+        }
+
+        let tables = &mut self.tables;
+        let id = *self.by_content.entry(content).or_insert_with(|| {
+            tables.push(table.clone());
+            tables.len() - 1
+        });
+        ids.raw_set(table.clone(), id as i64)?;
+        Ok(Some(id))
+    }
+
+    // Writes an encoding of `value` to `out` that is equal for structurally equal values. Returns
+    // `false` for values that can't be compared by content, e.g. functions or tables with
+    // metatables.
+    fn fingerprint(
+        &mut self,
+        lua: &'lua Lua,
+        value: &Value<'lua>,
+        out: &mut Vec<u8>,
+    ) -> Result<bool> {
+        match value {
+            Value::Nil => out.push(b'n'),
+            Value::Boolean(b) => out.extend_from_slice(if *b { b"t" } else { b"f" }),
+            Value::Integer(i) => {
+                out.push(b'i');
+                out.extend_from_slice(&i.to_le_bytes());
+            }
+            Value::Number(n) => {
+                out.push(b'd');
+                out.extend_from_slice(&n.to_bits().to_le_bytes());
+            }
+            Value::String(s) => {
+                out.push(b's');
+                out.extend_from_slice(&s.as_bytes().len().to_le_bytes());
+                out.extend_from_slice(s.as_bytes());
+            }
+            Value::LightUserData(ud) => {
+                out.push(b'l');
+                out.extend_from_slice(&(ud.0 as usize).to_le_bytes());
+            }
+            Value::Table(table) => match self.id(lua, table)? {
+                Some(id) => {
+                    out.push(b'r');
+                    out.extend_from_slice(&id.to_le_bytes());
+                }
+                None => return Ok(false),
+            },
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
 }
 
 impl<'lua> ser::Serializer for Serializer<'lua> {
@@ -55,6 +729,9 @@ impl<'lua> ser::Serializer for Serializer<'lua> {
     // primitive types
 
     fn serialize_bool(self, v: bool) -> Result<Self::Ok> {
+        if self.options.bool_as_int {
+            return Ok(Value::Integer(v as i64));
+        }
         Ok(v.to_lua(self.lua)?)
     }
 
@@ -74,6 +751,12 @@ impl<'lua> ser::Serializer for Serializer<'lua> {
         Ok(v.to_lua(self.lua)?)
     }
 
+    // 128-bit integers don't fit into Lua numbers, so they are serialized as decimal strings
+    fn serialize_i128(self, v: i128) -> Result<Self::Ok> {
+        self.report_coercion(v);
+        self.serialize_str(&v.to_string())
+    }
+
     fn serialize_u8(self, v: u8) -> Result<Self::Ok> {
         Ok(v.to_lua(self.lua)?)
     }
@@ -87,23 +770,86 @@ impl<'lua> ser::Serializer for Serializer<'lua> {
     }
 
     fn serialize_u64(self, v: u64) -> Result<Self::Ok> {
+        if v > i64::MAX as u64 {
+            self.report_coercion(v);
+        }
         Ok(v.to_lua(self.lua)?)
     }
 
+    fn serialize_u128(self, v: u128) -> Result<Self::Ok> {
+        self.report_coercion(v);
+        self.serialize_str(&v.to_string())
+    }
+
     fn serialize_f32(self, v: f32) -> Result<Self::Ok> {
         Ok(v.to_lua(self.lua)?)
     }
 
     fn serialize_f64(self, v: f64) -> Result<Self::Ok> {
-        Ok(v.to_lua(self.lua)?)
+        let value = v.to_lua(self.lua)?;
+        if self.options.strict_float_precision {
+            // push the number through Lua and read it back
+            let mut scratch = self.float_scratch.borrow_mut();
+            let scratch = match &mut *scratch {
+                Some(scratch) => scratch,
+                None => scratch.insert(self.lua.create_table()?),
+            };
+            scratch.raw_set(1, value.clone())?;
+            let back = match scratch.raw_get::<_, Value>(1)? {
+                Value::Integer(i) => i as f64,
+                Value::Number(n) => n,
+                _ => f64::NAN,
+            };
+            check_float_precision(v, back)?;
+        }
+        Ok(value)
     }
 
     fn serialize_char(self, v: char) -> Result<Self::Ok> {
-        Ok(v.to_string().to_lua(self.lua)?)
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
     }
 
     fn serialize_str(self, v: &str) -> Result<Self::Ok> {
-        Ok(v.to_lua(self.lua)?)
+        let transformed;
+        let v = match &self.key_transform {
+            Some(transform) if self.map_key => {
+                transformed = transform(v);
+                transformed.as_str()
+            }
+            _ => v,
+        };
+        if is_nil_string(v, self.map_key || self.in_seq, &self.options) {
+            return Ok(Value::Nil);
+        }
+        if let Some(cache) = &self.options.string_cache {
+            return Ok(Value::String(cache.get(self.lua, v)?));
+        }
+        if !self.options.intern_strings {
+            return Ok(v.to_lua(self.lua)?);
+        }
+
+        let mut interned = self.interned.borrow_mut();
+        if let Some(s) = interned.get(v) {
+            return Ok(Value::String(s.clone()));
+        }
+        let s = self.lua.create_string(v)?;
+        interned.insert(v.to_string(), s.clone());
+        Ok(Value::String(s))
+    }
+
+    // Format into a reused buffer instead of allocating a new `String` for every value.
+    fn collect_str<T>(self, value: &T) -> Result<Self::Ok>
+    where
+        T: ?Sized + std::fmt::Display,
+    {
+        use std::fmt::Write;
+
+        let buffer = self.buffer.clone();
+        let mut buffer = buffer.borrow_mut();
+        buffer.clear();
+        write!(buffer, "{}", value)
+            .map_err(|_| Error::Message("Display implementation returned an error".to_string()))?;
+        self.serialize_str(&buffer)
     }
 
     // Serialize a byte array as an array of bytes. Could also use a base64
@@ -146,35 +892,58 @@ impl<'lua> ser::Serializer for Serializer<'lua> {
         self.serialize_str(variant)
     }
 
-    fn serialize_newtype_struct<T>(self, _name: &'static str, value: &T) -> Result<Self::Ok>
+    fn serialize_newtype_struct<T>(self, name: &'static str, value: &T) -> Result<Self::Ok>
     where
         T: ?Sized + Serialize,
     {
+        if name == custom::TOKEN {
+            if let Some(result) = custom::take()
+                .and_then(|value| self.options.custom.serialize(value.as_ref(), self.lua))
+            {
+                return result;
+            }
+        }
         value.serialize(self)
     }
 
     fn serialize_newtype_variant<T>(
         self,
         _name: &'static str,
-        _variant_index: u32,
+        variant_index: u32,
         variant: &'static str,
         value: &T,
     ) -> Result<Self::Ok>
     where
         T: ?Sized + Serialize,
     {
-        let value = value.serialize(Serializer { lua: self.lua })?;
-        let table = self.lua.create_table()?;
-        table.set(variant, value)?;
-        Ok(Value::Table(table))
+        self.check_variant_key(variant)?;
+        let value = value.serialize(self.clone())?;
+        let is_table = matches!(value, Value::Table(_));
+        check_newtype_variant_content(variant, is_table, &self.options)?;
+        match (&self.options.enum_representation, value) {
+            (EnumRepresentation::ExternallyTagged, value) => {
+                let table = self.lua.create_table()?;
+                table.set(variant, value)?;
+                self.finish(table)
+            }
+            (_, Value::Table(table)) => {
+                self.set_variant_tags(&table, variant, variant_index)?;
+                self.finish(table)
+            }
+            (_, _) => unreachable!("checked by check_newtype_variant_content"),
+        }
     }
 
     fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
         let table = self.lua.create_table()?;
+        let index = if self.options.zero_based_arrays { 0 } else { 1 };
         Ok(SeqSerializer {
-            lua: self.lua,
+            ser: Serializer {
+                in_seq: true,
+                ..self.nested()?
+            },
             table,
-            index: 1,
+            index,
         })
     }
 
@@ -193,45 +962,86 @@ impl<'lua> ser::Serializer for Serializer<'lua> {
     fn serialize_tuple_variant(
         self,
         _name: &'static str,
-        _variant_index: u32,
+        variant_index: u32,
         variant: &'static str,
         _len: usize,
     ) -> Result<Self::SerializeTupleVariant> {
+        self.check_variant_key(variant)?;
         let table = self.lua.create_table()?;
+        let index = if self.options.zero_based_arrays { 0 } else { 1 };
         Ok(TupleVariantSerializer {
-            lua: self.lua,
+            ser: Serializer {
+                in_seq: true,
+                ..self.nested()?
+            },
             table,
-            index: 1,
+            index,
             name: variant.to_string(),
+            variant_index,
         })
     }
 
     fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
         let table = self.lua.create_table()?;
+        let entries = if self.options.deterministic {
+            Some(Vec::new())
+        } else {
+            None
+        };
         Ok(MapSerializer {
-            lua: self.lua,
+            ser: self.nested()?,
             table,
             key: None,
+            entries,
+            handle: None,
+            fields: None,
         })
     }
-    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Self::SerializeStruct> {
-        self.serialize_map(Some(len))
+
+    fn serialize_struct(self, name: &'static str, len: usize) -> Result<Self::SerializeStruct> {
+        let struct_table = self.struct_tables.borrow().get(name).cloned();
+        let (table, fields) = match struct_table {
+            Some(f) => (f.call(())?, None),
+            None => (
+                self.lua.create_table()?,
+                Some((name, Vec::with_capacity(len))),
+            ),
+        };
+        let handle = if is_userdata_struct(name, self.depth, &self.options) {
+            Some(name)
+        } else {
+            None
+        };
+        Ok(MapSerializer {
+            ser: self.nested()?,
+            table,
+            key: None,
+            entries: None,
+            handle,
+            fields,
+        })
     }
 
     fn serialize_struct_variant(
         self,
         _name: &'static str,
-        _variant_index: u32,
+        variant_index: u32,
         variant: &'static str,
         _len: usize,
     ) -> Result<Self::SerializeStructVariant> {
+        self.check_variant_key(variant)?;
         let table = self.lua.create_table()?;
         Ok(StructVariantSerializer {
-            lua: self.lua,
+            ser: self.nested()?,
             table,
             name: variant.to_owned(),
+            variant_index,
         })
     }
+
+    fn is_human_readable(&self) -> bool {
+        self.options.human_readable
+    }
 }
 
 impl<'lua> ser::SerializeSeq for SeqSerializer<'lua> {
@@ -242,14 +1052,14 @@ impl<'lua> ser::SerializeSeq for SeqSerializer<'lua> {
     where
         T: ?Sized + Serialize,
     {
-        let value = value.serialize(Serializer { lua: self.lua })?;
+        let value = self.ser.seq_element(value.serialize(self.ser.clone())?)?;
         self.table.set(self.index, value)?;
         self.index += 1;
         Ok(())
     }
 
     fn end(self) -> Result<Self::Ok> {
-        Ok(Value::Table(self.table))
+        self.ser.finish(self.table)
     }
 }
 
@@ -293,16 +1103,244 @@ impl<'lua> ser::SerializeTupleVariant for TupleVariantSerializer<'lua> {
     where
         T: ?Sized + Serialize,
     {
-        let value = value.serialize(Serializer { lua: self.lua })?;
+        let value = self.ser.seq_element(value.serialize(self.ser.clone())?)?;
         self.table.set(self.index, value)?;
         self.index += 1;
         Ok(())
     }
 
     fn end(self) -> Result<Self::Ok> {
-        let map = self.lua.create_table()?;
-        map.set(self.name, self.table)?;
-        Ok(Value::Table(map))
+        match &self.ser.options.enum_representation {
+            EnumRepresentation::ExternallyTagged => {
+                let map = self.ser.lua.create_table()?;
+                map.set(self.name, self.table)?;
+                self.ser.finish(map)
+            }
+            _ => {
+                self.ser
+                    .set_variant_tags(&self.table, &self.name, self.variant_index)?;
+                self.ser.finish(self.table)
+            }
+        }
+    }
+}
+
+impl<'lua> MapSerializer<'lua> {
+    fn insert(&mut self, key: Value<'lua>, value: Value<'lua>) -> Result<()> {
+        if self.ser.options.error_on_duplicate_keys
+            && self.table.raw_get::<_, Value>(key.clone())? != Value::Nil
+        {
+            return Err(duplicate_key(&key_to_string(&key)));
+        }
+        if value == Value::Nil {
+            let key = key_to_string(&key);
+            self.ser.report.borrow_mut().dropped_keys.push(key);
+        }
+        let value = self.ser.dedup(value)?;
+        self.table.set(key, value)?;
+        Ok(())
+    }
+}
+
+impl<'lua> MapSerializer<'lua> {
+    fn insert_entry(&mut self, key: Value<'lua>, value: Value<'lua>) -> Result<()> {
+        let value = match (value, self.ser.options.none_placeholder) {
+            (Value::Nil, NonePlaceholder::Nil) => NULL,
+            (Value::Nil, NonePlaceholder::False) => Value::Boolean(false),
+            (value, _) => value,
+        };
+        match &mut self.entries {
+            Some(entries) => {
+                entries.push((key, value));
+                Ok(())
+            }
+            None => self.insert(key, value),
+        }
+    }
+}
+
+// Orders keys by type (booleans, numbers, strings, others) and then by value.
+fn compare_keys(a: &Value, b: &Value) -> Ordering {
+    fn rank(v: &Value) -> u8 {
+        match v {
+            Value::Boolean(_) => 0,
+            Value::Integer(_) | Value::Number(_) => 1,
+            Value::String(_) => 2,
+            _ => 3,
+        }
+    }
+
+    match (a, b) {
+        (Value::Boolean(a), Value::Boolean(b)) => a.cmp(b),
+        (Value::Integer(a), Value::Integer(b)) => a.cmp(b),
+        (Value::Integer(a), Value::Number(b)) => (*a as f64).total_cmp(b),
+        (Value::Number(a), Value::Integer(b)) => a.total_cmp(&(*b as f64)),
+        (Value::Number(a), Value::Number(b)) => a.total_cmp(b),
+        (Value::String(a), Value::String(b)) => a.as_bytes().cmp(b.as_bytes()),
+        (a, b) => rank(a).cmp(&rank(b)),
+    }
+}
+
+fn offset_int_key(key: i64, offset: i64) -> Result<i64> {
+    key.checked_add(offset).ok_or_else(|| {
+        Error::Message(format!(
+            "integer map key {} overflows with int_key_offset {}",
+            key, offset
+        ))
+    })
+}
+
+// Compiles a function that creates a table with room for `keys`. Lua can only pre-size the hash
+// part of a table in a table constructor, so the keys are set and cleared right away.
+fn struct_table<'lua>(lua: &'lua Lua, keys: &[Value<'lua>]) -> Result<Function<'lua>> {
+    let mut fields = String::new();
+    let mut clear = String::new();
+    for key in keys {
+        let s = match key {
+            Value::String(s) => s,
+            _ => continue,
+        };
+        let mut key = String::from("[\"");
+        for b in s.as_bytes() {
+            key.push_str(&format!("\\{}", b));
+        }
+        key.push_str("\"]");
+        fields.push_str(&format!("{} = false, ", key));
+        clear.push_str(&format!("t{} = nil ", key));
+    }
+    let source = format!("local t = {{ {}}} {}return t", fields, clear);
+    Ok(lua.load(&source).into_function()?)
+}
+
+// The rules below depend on the options only, and are shared with `can_serialize`, so that it
+// can't disagree with the serializer.
+
+/// The depth of the elements of a new sequence, map or struct at `depth`.
+pub(crate) fn nested_depth(depth: usize, options: &SerializerOptions) -> Result<usize> {
+    let depth = depth + 1;
+    match options.max_depth {
+        Some(max_depth) if depth > max_depth => Err(Error::Message(format!(
+            "maximum depth of {} exceeded",
+            max_depth
+        ))),
+        _ => Ok(depth),
+    }
+}
+
+/// Errors for variants with data that are used as map keys with `enum_keys_as_string`.
+pub(crate) fn check_variant_key(
+    variant: &str,
+    map_key: bool,
+    options: &SerializerOptions,
+) -> Result<()> {
+    if map_key && options.enum_keys_as_string {
+        return Err(Error::Message(format!(
+            "cannot serialize enum variant `{}` as a map key, it carries data",
+            variant
+        )));
+    }
+    Ok(())
+}
+
+/// Whether the string `v` is written as `nil`, see `empty_string_as_nil`. `keep` is set for map
+/// keys and sequence elements.
+pub(crate) fn is_nil_string(v: &str, keep: bool, options: &SerializerOptions) -> bool {
+    v.is_empty() && options.empty_string_as_nil && !keep
+}
+
+/// Errors if the content of a newtype variant can't carry its tag.
+pub(crate) fn check_newtype_variant_content(
+    variant: &str,
+    is_table: bool,
+    options: &SerializerOptions,
+) -> Result<()> {
+    match options.enum_representation {
+        EnumRepresentation::ExternallyTagged => Ok(()),
+        _ if is_table => Ok(()),
+        _ => Err(Error::Message(format!(
+            "cannot serialize newtype variant `{}` as internally tagged, its content is not a table",
+            variant
+        ))),
+    }
+}
+
+/// Whether the struct `name` at `depth` ends up in a `StructHandle`, see `structs_as_userdata`.
+pub(crate) fn is_userdata_struct(name: &str, depth: usize, options: &SerializerOptions) -> bool {
+    depth > 0 && options.structs_as_userdata.contains(name)
+}
+
+pub(crate) fn duplicate_key(key: &str) -> Error {
+    Error::Message(format!("duplicate key `{}`", key))
+}
+
+/// A number or boolean map key, before `int_key_offset` and `stringify_map_keys` are applied.
+pub(crate) enum ScalarKey {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl ScalarKey {
+    /// Applies `int_key_offset` and `stringify_map_keys`, returning the key as string if it is
+    /// stringified.
+    pub(crate) fn normalize(
+        self,
+        options: &SerializerOptions,
+    ) -> Result<std::result::Result<ScalarKey, String>> {
+        let key = match self {
+            ScalarKey::Int(i) => ScalarKey::Int(offset_int_key(i, options.int_key_offset)?),
+            key => key,
+        };
+        if !options.stringify_map_keys {
+            return Ok(Ok(key));
+        }
+        Ok(Err(match key {
+            ScalarKey::Int(i) => i.to_string(),
+            ScalarKey::Float(n) => n.to_string(),
+            ScalarKey::Bool(b) => b.to_string(),
+        }))
+    }
+}
+
+/// Errors if `reject_sparse_int_keys` is set and the integer `keys` of a map aren't contiguous.
+pub(crate) fn check_dense_int_keys(
+    keys: impl IntoIterator<Item = i64>,
+    options: &SerializerOptions,
+) -> Result<()> {
+    if !options.reject_sparse_int_keys {
+        return Ok(());
+    }
+    let mut keys = keys.into_iter().collect::<Vec<_>>();
+    keys.sort_unstable();
+    let start = if options.zero_based_arrays { 0 } else { 1 };
+    for (expected, key) in (start..).zip(keys) {
+        if key != expected {
+            return Err(Error::Message(format!(
+                "sparse integer map keys, expected key {} but found {}",
+                expected, key
+            )));
+        }
+    }
+    Ok(())
+}
+
+impl<'lua> MapSerializer<'lua> {
+    fn serialize_map_key<T>(&self, key: &T) -> Result<Value<'lua>>
+    where
+        T: ?Sized + Serialize,
+    {
+        let key = match key.serialize(self.ser.key_serializer())? {
+            Value::Integer(i) => ScalarKey::Int(i),
+            Value::Number(n) => ScalarKey::Float(n),
+            Value::Boolean(b) => ScalarKey::Bool(b),
+            key => return Ok(key),
+        };
+        Ok(match key.normalize(&self.ser.options)? {
+            Ok(ScalarKey::Int(i)) => Value::Integer(i),
+            Ok(ScalarKey::Float(n)) => Value::Number(n),
+            Ok(ScalarKey::Bool(b)) => Value::Boolean(b),
+            Err(s) => Value::String(self.ser.lua.create_string(&s)?),
+        })
     }
 }
 
@@ -314,7 +1352,7 @@ impl<'lua> ser::SerializeMap for MapSerializer<'lua> {
     where
         T: ?Sized + Serialize,
     {
-        let key = key.serialize(Serializer { lua: self.lua })?;
+        let key = self.serialize_map_key(key)?;
         self.key.replace(key);
         Ok(())
     }
@@ -323,13 +1361,12 @@ impl<'lua> ser::SerializeMap for MapSerializer<'lua> {
     where
         T: ?Sized + Serialize,
     {
-        let value = value.serialize(Serializer { lua: self.lua })?;
+        let value = value.serialize(self.ser.clone())?;
         let key = self
             .key
             .take()
             .expect("serialize_key must be called before serialize_value");
-        self.table.set(key, value)?;
-        Ok(())
+        self.insert_entry(key, value)
     }
 
     fn serialize_entry<K: Serialize + ?Sized, V: Serialize + ?Sized>(
@@ -337,14 +1374,30 @@ impl<'lua> ser::SerializeMap for MapSerializer<'lua> {
         key: &K,
         value: &V,
     ) -> Result<()> {
-        let key = key.serialize(Serializer { lua: self.lua })?;
-        let value = value.serialize(Serializer { lua: self.lua })?;
-        self.table.set(key, value)?;
-        Ok(())
+        let key = self.serialize_map_key(key)?;
+        let value = value.serialize(self.ser.clone())?;
+        self.insert_entry(key, value)
     }
 
-    fn end(self) -> Result<Self::Ok> {
-        Ok(Value::Table(self.table))
+    fn end(mut self) -> Result<Self::Ok> {
+        if let Some(mut entries) = self.entries.take() {
+            entries.sort_by(|(a, _), (b, _)| compare_keys(a, b));
+            for (key, value) in entries {
+                self.insert(key, value)?;
+            }
+        }
+        if self.ser.options.reject_sparse_int_keys {
+            let mut keys = Vec::new();
+            for pair in self.table.clone().pairs::<Value, Value>() {
+                match pair?.0 {
+                    Value::Integer(i) => keys.push(i),
+                    Value::Number(n) if n.fract() == 0.0 => keys.push(n as i64),
+                    _ => {}
+                }
+            }
+            check_dense_int_keys(keys, &self.ser.options)?;
+        }
+        self.ser.finish(self.table)
     }
 }
 
@@ -356,11 +1409,35 @@ impl<'lua> ser::SerializeStruct for MapSerializer<'lua> {
     where
         T: ?Sized + Serialize,
     {
-        serde::ser::SerializeMap::serialize_entry(self, key, value)
+        let value = match self
+            .ser
+            .struct_field_value(key, value.serialize(self.ser.clone())?)
+        {
+            Some(value) => value,
+            None => return Ok(()),
+        };
+        let key = self.ser.field_key(key)?;
+        if let Some((_, keys)) = &mut self.fields {
+            keys.push(key.clone());
+        }
+        self.insert(key, value)
     }
 
-    fn end(self) -> Result<Self::Ok> {
-        serde::ser::SerializeMap::end(self)
+    fn end(mut self) -> Result<Self::Ok> {
+        if let Some((name, keys)) = self.fields.take() {
+            let f = struct_table(self.ser.lua, &keys)?;
+            self.ser.struct_tables.borrow_mut().insert(name, f);
+        }
+        let lua = self.ser.lua;
+        let handle = self.handle;
+        match (handle, serde::ser::SerializeMap::end(self)?) {
+            (Some(name), Value::Table(table)) => {
+                let userdata = lua.create_userdata(StructHandle { name })?;
+                userdata.set_user_value(table)?;
+                Ok(Value::UserData(userdata))
+            }
+            (_, value) => Ok(value),
+        }
     }
 }
 
@@ -372,15 +1449,1308 @@ impl<'lua> ser::SerializeStructVariant for StructVariantSerializer<'lua> {
     where
         T: ?Sized + Serialize,
     {
-        let key = key.serialize(Serializer { lua: self.lua })?;
-        let value = value.serialize(Serializer { lua: self.lua })?;
+        let value = match self
+            .ser
+            .struct_field_value(key, value.serialize(self.ser.clone())?)
+        {
+            Some(value) => value,
+            None => return Ok(()),
+        };
+        let key = self.ser.field_key(key)?;
+        let value = self.ser.dedup(value)?;
         self.table.set(key, value)?;
         Ok(())
     }
 
     fn end(self) -> Result<Self::Ok> {
-        let map = self.lua.create_table()?;
-        map.set(self.name, self.table)?;
-        Ok(Value::Table(map))
+        match &self.ser.options.enum_representation {
+            EnumRepresentation::ExternallyTagged => {
+                let map = self.ser.lua.create_table()?;
+                map.set(self.name, self.table)?;
+                self.ser.finish(map)
+            }
+            _ => {
+                self.ser
+                    .set_variant_tags(&self.table, &self.name, self.variant_index)?;
+                self.ser.finish(self.table)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        check_float_precision, serialize_iter, set_serialized, set_serialized_with, to_value,
+        to_value_with, to_value_with_report, EnumRepresentation, NonePlaceholder,
+        SerializerOptions, NULL,
+    };
+    use crate::{from_value, from_value_with, DeserializerOptions};
+    use mlua::{Lua, Table, Value};
+    use serde::ser::{SerializeMap, Serializer};
+    use serde::{Deserialize, Serialize};
+    use std::cell::Cell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    #[derive(Serialize)]
+    struct Config {
+        name: &'static str,
+        timeout: Option<u32>,
+    }
+
+    fn as_table<'lua>(value: &Value<'lua>) -> Table<'lua> {
+        match value {
+            Value::Table(t) => t.clone(),
+            _ => panic!("expected table"),
+        }
+    }
+
+    fn serialize_config(lua: &Lua, none_placeholder: NonePlaceholder) -> Table<'_> {
+        let config = Config {
+            name: "test",
+            timeout: None,
+        };
+        let options = SerializerOptions {
+            none_placeholder,
+            ..Default::default()
+        };
+        as_table(&to_value_with(lua, &config, options).unwrap())
+    }
+
+    #[test]
+    fn none_placeholder_omit() {
+        let lua = Lua::new();
+        let table = serialize_config(&lua, NonePlaceholder::Omit);
+        assert!(!table.contains_key("timeout").unwrap());
+        assert_eq!("test", table.get::<_, String>("name").unwrap());
+
+        let value = to_value(
+            &lua,
+            Config {
+                name: "test",
+                timeout: None,
+            },
+        )
+        .unwrap();
+        assert!(!as_table(&value).contains_key("timeout").unwrap());
+    }
+
+    #[test]
+    fn none_placeholder_nil() {
+        let lua = Lua::new();
+        let table = serialize_config(&lua, NonePlaceholder::Nil);
+        assert_eq!(NULL, table.get::<_, Value>("timeout").unwrap());
+    }
+
+    #[test]
+    fn none_placeholder_false() {
+        let lua = Lua::new();
+        let table = serialize_config(&lua, NonePlaceholder::False);
+        assert_eq!(
+            Value::Boolean(false),
+            table.get::<_, Value>("timeout").unwrap()
+        );
+    }
+
+    #[test]
+    fn none_placeholder_struct_variant() {
+        #[derive(Serialize)]
+        enum Event {
+            Timeout { after: Option<u32>, retries: u8 },
+        }
+
+        let lua = Lua::new();
+        let event = Event::Timeout {
+            after: None,
+            retries: 1,
+        };
+        let field = |none_placeholder| {
+            let options = SerializerOptions {
+                none_placeholder,
+                ..Default::default()
+            };
+            let value = to_value_with(&lua, &event, options).unwrap();
+            let variant = as_table(&value).get::<_, Table>("Timeout").unwrap();
+            assert_eq!(1, variant.get::<_, u8>("retries").unwrap());
+            variant.get::<_, Value>("after").unwrap()
+        };
+        assert_eq!(Value::Nil, field(NonePlaceholder::Omit));
+        assert_eq!(NULL, field(NonePlaceholder::Nil));
+        assert_eq!(Value::Boolean(false), field(NonePlaceholder::False));
+    }
+
+    #[test]
+    fn references_serialize_like_owned_values() {
+        let lua = Lua::new();
+
+        let owned = vec![1i64, 2, 3];
+        let slice: &[i64] = &owned;
+        assert_eq!(
+            from_value::<Vec<i64>>(to_value(&lua, owned.clone()).unwrap()).unwrap(),
+            from_value::<Vec<i64>>(to_value(&lua, slice).unwrap()).unwrap(),
+        );
+
+        let owned = "foo".to_string();
+        let borrowed: &str = &owned;
+        assert_eq!(
+            to_value(&lua, owned.clone()).unwrap(),
+            to_value(&lua, borrowed).unwrap()
+        );
+
+        let owned = vec!["a".to_string(), "b".to_string()];
+        assert_eq!(
+            from_value::<Vec<String>>(to_value(&lua, owned.clone()).unwrap()).unwrap(),
+            from_value::<Vec<String>>(to_value(&lua, &owned).unwrap()).unwrap(),
+        );
+    }
+
+    #[test]
+    fn char_serializes_as_string() {
+        let lua = Lua::new();
+        assert_eq!(to_value(&lua, "ß").unwrap(), to_value(&lua, 'ß').unwrap());
+    }
+
+    #[test]
+    fn flatten_key_shadowing_named_field() {
+        #[derive(Serialize)]
+        struct Config {
+            name: String,
+            #[serde(flatten)]
+            extra: HashMap<String, String>,
+        }
+
+        let mut extra = HashMap::new();
+        extra.insert("name".to_string(), "other".to_string());
+        let config = Config {
+            name: "test".to_string(),
+            extra,
+        };
+
+        let lua = Lua::new();
+        assert!(to_value(&lua, &config).is_ok());
+
+        let options = SerializerOptions {
+            error_on_duplicate_keys: true,
+            ..Default::default()
+        };
+        let err = to_value_with(&lua, &config, options.clone()).unwrap_err();
+        assert_eq!("duplicate key `name`", err.to_string());
+
+        struct SameIndex;
+
+        impl Serialize for SameIndex {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                let mut map = serializer.serialize_map(Some(2))?;
+                map.serialize_entry(&1, "a")?;
+                map.serialize_entry(&1, "b")?;
+                map.end()
+            }
+        }
+
+        let err = to_value_with(&lua, SameIndex, options).unwrap_err();
+        assert_eq!("duplicate key `1`", err.to_string());
+    }
+
+    struct Counted<'a>(&'static str, &'a Cell<usize>);
+
+    impl Serialize for Counted<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            self.1.set(self.1.get() + 1);
+            serializer.serialize_str(self.0)
+        }
+    }
+
+    #[test]
+    fn map_entries_serialize_once() {
+        // serializes through `collect_map`, like `HashMap` does
+        struct Pairs<'a>(Vec<(Counted<'a>, Counted<'a>)>);
+
+        impl Serialize for Pairs<'_> {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_map(self.0.iter().map(|(k, v)| (k, v)))
+            }
+        }
+
+        let keys = Cell::new(0);
+        let values = Cell::new(0);
+        let map = Pairs(vec![
+            (Counted("a", &keys), Counted("1", &values)),
+            (Counted("b", &keys), Counted("2", &values)),
+        ]);
+
+        let lua = Lua::new();
+        let table = as_table(&to_value(&lua, &map).unwrap());
+        assert_eq!(2, keys.get());
+        assert_eq!(2, values.get());
+        assert_eq!("1", table.get::<_, String>("a").unwrap());
+        assert_eq!("2", table.get::<_, String>("b").unwrap());
+    }
+
+    #[test]
+    fn map_split_key_value_serialize_once() {
+        struct SplitMap<'a>(&'a Cell<usize>);
+
+        impl Serialize for SplitMap<'_> {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                let mut map = serializer.serialize_map(Some(2))?;
+                map.serialize_key(&Counted("a", self.0))?;
+                map.serialize_value(&Counted("1", self.0))?;
+                map.serialize_key(&Counted("b", self.0))?;
+                map.serialize_value(&Counted("2", self.0))?;
+                map.end()
+            }
+        }
+
+        let count = Cell::new(0);
+        let lua = Lua::new();
+        let table = as_table(&to_value(&lua, SplitMap(&count)).unwrap());
+        assert_eq!(4, count.get());
+        assert_eq!("1", table.get::<_, String>("a").unwrap());
+        assert_eq!("2", table.get::<_, String>("b").unwrap());
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    enum Shape {
+        Circle { radius: i64 },
+        Point(Position),
+        Line(i64, i64),
+        Empty,
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Position {
+        x: i64,
+        y: i64,
+    }
+
+    #[test]
+    fn internally_tagged_round_trip() {
+        let lua = Lua::new();
+        let representation = EnumRepresentation::InternallyTagged {
+            tag: "type".to_string(),
+        };
+        let ser_options = SerializerOptions {
+            enum_representation: representation.clone(),
+            ..Default::default()
+        };
+
+        let shapes = vec![
+            Shape::Circle { radius: 1 },
+            Shape::Point(Position { x: 1, y: 2 }),
+            Shape::Line(3, 4),
+            Shape::Empty,
+        ];
+        for shape in shapes {
+            let value = to_value_with(&lua, &shape, ser_options.clone()).unwrap();
+            let de_options = DeserializerOptions {
+                enum_representation: representation.clone(),
+                ..Default::default()
+            };
+            let result: Shape = from_value_with(value, de_options).unwrap();
+            assert_eq!(shape, result);
+        }
+
+        let value = to_value_with(&lua, Shape::Circle { radius: 1 }, ser_options).unwrap();
+        let table = as_table(&value);
+        assert_eq!("Circle", table.get::<_, String>("type").unwrap());
+        assert_eq!(1, table.get::<_, i64>("radius").unwrap());
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    enum Payload {
+        Map(HashMap<String, u8>),
+        Strict(Strict),
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    #[serde(deny_unknown_fields)]
+    struct Strict {
+        x: u8,
+    }
+
+    #[test]
+    fn internally_tagged_newtype_content() {
+        let lua = Lua::new();
+        let representation = EnumRepresentation::InternallyTagged {
+            tag: "type".to_string(),
+        };
+
+        let mut map = HashMap::new();
+        map.insert("x".to_string(), 1);
+        let cases = vec![
+            (r#"return { type = "Map", x = 1 }"#, Payload::Map(map)),
+            (
+                r#"return { type = "Strict", x = 1 }"#,
+                Payload::Strict(Strict { x: 1 }),
+            ),
+        ];
+        for (source, expected) in cases {
+            let value = lua.load(source).eval().unwrap();
+            let options = DeserializerOptions {
+                enum_representation: representation.clone(),
+                ..Default::default()
+            };
+            assert_eq!(expected, from_value_with(value, options).unwrap());
+        }
+
+        let options = SerializerOptions {
+            enum_representation: representation,
+            ..Default::default()
+        };
+        let mut map = HashMap::new();
+        map.insert("type".to_string(), 1);
+        let err = to_value_with(&lua, Payload::Map(map), options).unwrap_err();
+        assert_eq!(
+            "cannot tag variant `Map`, its content already has a key `type`",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn tagged_with_name_and_index() {
+        let lua = Lua::new();
+        let representation = EnumRepresentation::Tagged {
+            name_key: "__variant".to_string(),
+            index_key: "__index".to_string(),
+        };
+        let ser_options = SerializerOptions {
+            enum_representation: representation.clone(),
+            ..Default::default()
+        };
+        let de_options = DeserializerOptions {
+            enum_representation: representation.clone(),
+            ..Default::default()
+        };
+
+        let shapes = vec![
+            Shape::Circle { radius: 1 },
+            Shape::Point(Position { x: 1, y: 2 }),
+            Shape::Line(3, 4),
+            Shape::Empty,
+        ];
+        for shape in shapes {
+            let value = to_value_with(&lua, &shape, ser_options.clone()).unwrap();
+            let result: Shape = from_value_with(value, de_options.clone()).unwrap();
+            assert_eq!(shape, result);
+        }
+
+        let value = to_value_with(&lua, Shape::Line(3, 4), ser_options).unwrap();
+        let table = as_table(&value);
+        assert_eq!("Line", table.get::<_, String>("__variant").unwrap());
+        assert_eq!(2, table.get::<_, i64>("__index").unwrap());
+        assert_eq!(3, table.get::<_, i64>(1).unwrap());
+
+        // either key is enough
+        table.set("__variant", Value::Nil).unwrap();
+        let result: Shape = from_value_with(value, de_options.clone()).unwrap();
+        assert_eq!(Shape::Line(3, 4), result);
+
+        let value = lua
+            .load(r#"return { __variant = "Circle", radius = 5 }"#)
+            .eval()
+            .unwrap();
+        let result: Shape = from_value_with(value, de_options.clone()).unwrap();
+        assert_eq!(Shape::Circle { radius: 5 }, result);
+    }
+
+    #[test]
+    fn tagged_newtype_content() {
+        let lua = Lua::new();
+        let representation = EnumRepresentation::Tagged {
+            name_key: "__variant".to_string(),
+            index_key: "__index".to_string(),
+        };
+        let ser_options = SerializerOptions {
+            enum_representation: representation.clone(),
+            ..Default::default()
+        };
+
+        let mut map = HashMap::new();
+        map.insert("x".to_string(), 1);
+        for payload in [Payload::Map(map), Payload::Strict(Strict { x: 1 })] {
+            let value = to_value_with(&lua, &payload, ser_options.clone()).unwrap();
+            let options = DeserializerOptions {
+                enum_representation: representation.clone(),
+                ..Default::default()
+            };
+            assert_eq!(payload, from_value_with(value, options).unwrap());
+        }
+
+        let mut map = HashMap::new();
+        map.insert("__index".to_string(), 1);
+        let err = to_value_with(&lua, Payload::Map(map), ser_options).unwrap_err();
+        assert_eq!(
+            "cannot tag variant `Map`, its content already has a key `__index`",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn plain_data_only() {
+        fn assert_plain(value: Value) {
+            if let Value::Table(table) = value {
+                assert!(table.get_metatable().is_none());
+                for pair in table.pairs::<Value, Value>() {
+                    assert_plain(pair.unwrap().1);
+                }
+            }
+        }
+
+        let lua = Lua::new();
+        let options = SerializerOptions {
+            plain_data_only: true,
+            ..Default::default()
+        };
+        let shapes = vec![
+            Shape::Circle { radius: 1 },
+            Shape::Point(Position { x: 1, y: 2 }),
+            Shape::Line(3, 4),
+        ];
+        assert_plain(to_value_with(&lua, &shapes, options).unwrap());
+    }
+
+    #[test]
+    fn report_dropped_keys() {
+        let lua = Lua::new();
+        let mut map = HashMap::new();
+        map.insert("present", Some(1));
+        map.insert("missing", None);
+        let (value, report) =
+            to_value_with_report(&lua, &map, SerializerOptions::default()).unwrap();
+        let table = as_table(&value);
+        assert!(!table.contains_key("missing").unwrap());
+        assert_eq!(vec!["missing".to_string()], report.dropped_keys);
+        assert!(report.large_int_coercions.is_empty());
+
+        let config = Config {
+            name: "test",
+            timeout: None,
+        };
+        let (_, report) =
+            to_value_with_report(&lua, &config, SerializerOptions::default()).unwrap();
+        assert_eq!(vec!["timeout".to_string()], report.dropped_keys);
+
+        #[derive(Serialize)]
+        enum Event {
+            Timeout { after: Option<u32>, retries: u8 },
+        }
+        let event = Event::Timeout {
+            after: None,
+            retries: 1,
+        };
+        let (_, report) = to_value_with_report(&lua, &event, SerializerOptions::default()).unwrap();
+        assert_eq!(vec!["after".to_string()], report.dropped_keys);
+
+        let mut map = HashMap::new();
+        map.insert(7, None::<u8>);
+        let (_, report) = to_value_with_report(&lua, &map, SerializerOptions::default()).unwrap();
+        assert_eq!(vec!["7".to_string()], report.dropped_keys);
+    }
+
+    #[test]
+    fn report_large_int_coercions() {
+        let lua = Lua::new();
+        let (_, report) =
+            to_value_with_report(&lua, (1u64, u64::MAX, 7i128), SerializerOptions::default())
+                .unwrap();
+        assert!(report.dropped_keys.is_empty());
+        assert_eq!(
+            vec![u64::MAX.to_string(), "7".to_string()],
+            report.large_int_coercions
+        );
+    }
+
+    #[test]
+    fn zero_based_arrays() {
+        let lua = Lua::new();
+        let input = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+
+        let value = to_value(&lua, &input).unwrap();
+        let table = as_table(&value);
+        assert_eq!("a", table.get::<_, String>(1).unwrap());
+        assert!(!table.contains_key(0).unwrap());
+
+        let options = SerializerOptions {
+            zero_based_arrays: true,
+            ..Default::default()
+        };
+        let value = to_value_with(&lua, &input, options).unwrap();
+        let table = as_table(&value);
+        assert_eq!("a", table.get::<_, String>(0).unwrap());
+        assert_eq!("c", table.get::<_, String>(2).unwrap());
+        assert!(!table.contains_key(3).unwrap());
+
+        let options = DeserializerOptions {
+            zero_based_arrays: true,
+            ..Default::default()
+        };
+        let output: Vec<String> = from_value_with(value.clone(), options.clone()).unwrap();
+        assert_eq!(input, output);
+
+        // any-typed targets see the same sequence instead of a map with a `0` key
+        let json: serde_json::Value = from_value_with(value, options.clone()).unwrap();
+        assert_eq!(serde_json::json!(input), json);
+
+        let empty = to_value_with(
+            &lua,
+            Vec::<String>::new(),
+            SerializerOptions {
+                zero_based_arrays: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(from_value_with::<Vec<String>>(empty, options.clone())
+            .unwrap()
+            .is_empty());
+
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        enum Move {
+            To(i64, i64),
+        }
+        let ser_options = SerializerOptions {
+            zero_based_arrays: true,
+            ..Default::default()
+        };
+        let value = to_value_with(&lua, Move::To(5, 6), ser_options).unwrap();
+        let to = as_table(&value).get::<_, Table>("To").unwrap();
+        assert_eq!(5, to.get::<_, i64>(0).unwrap());
+        assert_eq!(6, to.get::<_, i64>(1).unwrap());
+        assert!(!to.contains_key(2).unwrap());
+        assert_eq!(Move::To(5, 6), from_value_with(value, options).unwrap());
+    }
+
+    #[test]
+    fn renamed_variants() {
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        enum Format {
+            #[serde(rename = "legacy")]
+            Legacy,
+            #[serde(rename = "legacy_v2")]
+            LegacyV2(u8),
+            #[serde(rename = "custom")]
+            Custom { name: String },
+        }
+
+        let lua = Lua::new();
+
+        let value = to_value(&lua, Format::Legacy).unwrap();
+        assert_eq!("legacy", lua.unpack::<String>(value.clone()).unwrap());
+        assert_eq!(Format::Legacy, from_value(value).unwrap());
+
+        let value = to_value(&lua, Format::LegacyV2(2)).unwrap();
+        let table = as_table(&value);
+        assert_eq!(2, table.get::<_, u8>("legacy_v2").unwrap());
+        assert!(!table.contains_key("LegacyV2").unwrap());
+        assert_eq!(Format::LegacyV2(2), from_value(value).unwrap());
+
+        let input = Format::Custom {
+            name: "x".to_string(),
+        };
+        let value = to_value(&lua, &input).unwrap();
+        let table = as_table(&value);
+        assert!(table.contains_key("custom").unwrap());
+        assert_eq!(input, from_value(value).unwrap());
+    }
+
+    #[test]
+    fn intern_strings() {
+        let lua = Lua::new();
+        let input: Vec<String> = (0..100).map(|i| format!("state{}", i % 2)).collect();
+        let options = SerializerOptions {
+            intern_strings: true,
+            ..Default::default()
+        };
+        let value = to_value_with(&lua, &input, options).unwrap();
+
+        let table = as_table(&value);
+        let rawequal: mlua::Function = lua
+            .load("return function(a, b) return rawequal(a, b) end")
+            .eval()
+            .unwrap();
+        let first: Value = table.get(1).unwrap();
+        let third: Value = table.get(3).unwrap();
+        assert!(rawequal.call::<_, bool>((first, third)).unwrap());
+
+        let output: Vec<String> = from_value(value).unwrap();
+        assert_eq!(input, output);
+    }
+
+    #[test]
+    fn enum_keys_as_string() {
+        #[derive(Serialize, PartialEq, Eq, Hash)]
+        enum Color {
+            Red,
+            Green,
+            Custom(u8),
+        }
+
+        let lua = Lua::new();
+        let options = SerializerOptions {
+            enum_keys_as_string: true,
+            ..Default::default()
+        };
+
+        let mut map = HashMap::new();
+        map.insert(Color::Red, 1);
+        map.insert(Color::Green, 2);
+        let table = as_table(&to_value_with(&lua, &map, options.clone()).unwrap());
+        assert_eq!(1, table.get::<_, i32>("Red").unwrap());
+        assert_eq!(2, table.get::<_, i32>("Green").unwrap());
+
+        let mut map = HashMap::new();
+        map.insert(Color::Custom(7), 1);
+        assert!(to_value(&lua, &map).is_ok());
+        let err = to_value_with(&lua, &map, options.clone()).unwrap_err();
+        assert_eq!(
+            "cannot serialize enum variant `Custom` as a map key, it carries data",
+            err.to_string()
+        );
+
+        // data-carrying variants are fine as values
+        let mut map = HashMap::new();
+        map.insert("color", Color::Custom(7));
+        assert!(to_value_with(&lua, &map, options.clone()).is_ok());
+    }
+
+    #[test]
+    fn bool_as_int() {
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct Flags {
+            on: bool,
+            off: bool,
+        }
+
+        let lua = Lua::new();
+        let input = Flags {
+            on: true,
+            off: false,
+        };
+
+        let table = as_table(&to_value(&lua, &input).unwrap());
+        assert_eq!(Value::Boolean(true), table.get::<_, Value>("on").unwrap());
+
+        let options = SerializerOptions {
+            bool_as_int: true,
+            ..Default::default()
+        };
+        let value = to_value_with(&lua, &input, options).unwrap();
+        let table = as_table(&value);
+        assert_eq!(Value::Integer(1), table.get::<_, Value>("on").unwrap());
+        assert_eq!(Value::Integer(0), table.get::<_, Value>("off").unwrap());
+
+        assert!(from_value::<Flags>(value.clone()).is_err());
+        let options = DeserializerOptions {
+            int_as_bool: true,
+            ..Default::default()
+        };
+        assert_eq!(input, from_value_with(value, options).unwrap());
+    }
+
+    fn round_trip_structs(n: i32) {
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct Point {
+            x: i32,
+            y: i32,
+            label: String,
+        }
+
+        let lua = Lua::new();
+        let input: Vec<Point> = (0..n)
+            .map(|i| Point {
+                x: i,
+                y: -i,
+                label: format!("p{}", i % 10),
+            })
+            .collect();
+        let value = to_value(&lua, &input).unwrap();
+        let output: Vec<Point> = from_value(value).unwrap();
+        assert_eq!(input, output);
+    }
+
+    #[test]
+    fn many_structs() {
+        round_trip_structs(100_000);
+    }
+
+    // Takes about half a minute in debug builds, run with `cargo test --release -- --ignored`.
+    #[test]
+    #[ignore]
+    fn million_structs() {
+        round_trip_structs(1_000_000);
+    }
+
+    #[test]
+    fn presized_struct_tables() {
+        #[derive(Serialize)]
+        struct Entry {
+            #[serde(rename = "na\"]\\me")]
+            name: &'static str,
+            note: Option<&'static str>,
+        }
+
+        let lua = Lua::new();
+        let input = [
+            Entry {
+                name: "a",
+                note: Some("first"),
+            },
+            Entry {
+                name: "b",
+                note: None,
+            },
+        ];
+        let value = to_value(&lua, &input).unwrap();
+        lua.globals().set("entries", value).unwrap();
+        let keys: String = lua
+            .load(
+                r#"
+                local keys = {}
+                for k in pairs(entries[2]) do keys[#keys + 1] = k end
+                return table.concat(keys, ",")
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!("na\"]\\me", keys);
+    }
+
+    #[test]
+    fn max_depth() {
+        let lua = Lua::new();
+        let mut nested = serde_json::json!([1]);
+        for _ in 0..9 {
+            nested = serde_json::json!([nested]);
+        }
+        let options = |max_depth| SerializerOptions {
+            max_depth: Some(max_depth),
+            ..Default::default()
+        };
+
+        assert!(to_value(&lua, &nested).is_ok());
+        assert!(to_value_with(&lua, &nested, options(10)).is_ok());
+        let err = to_value_with(&lua, &nested, options(9)).unwrap_err();
+        assert_eq!("maximum depth of 9 exceeded", err.to_string());
+    }
+
+    #[test]
+    fn strict_float_precision() {
+        let lua = Lua::new();
+        let options = SerializerOptions {
+            strict_float_precision: true,
+            ..Default::default()
+        };
+        let input = (0.1f64, 1e300f64, 2.5f32, f64::NAN);
+        let value = to_value_with(&lua, input, options).unwrap();
+        let back: (f64, f64, f32, f64) = from_value(value).unwrap();
+        assert_eq!((0.1, 1e300, 2.5), (back.0, back.1, back.2));
+        assert!(back.3.is_nan());
+
+        // simulate a Lua build with 32-bit floats
+        let v = 0.1f64;
+        let err = check_float_precision(v, v as f32 as f64).unwrap_err();
+        assert_eq!(
+            "float 0.1 lost precision as a Lua number (became 0.10000000149011612)",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn deterministic_maps() {
+        let lua = Lua::new();
+        let options = SerializerOptions {
+            deterministic: true,
+            plain_data_only: true,
+            ..Default::default()
+        };
+        assert!(crate::can_serialize(&HashMap::<u8, u8>::new(), &options).is_ok());
+
+        let keys: Vec<String> = (0..50).map(|i| format!("key{:02}", i)).collect();
+        let mut expected = None;
+        for _ in 0..5 {
+            // every HashMap gets a new random iteration order
+            let map: HashMap<&str, usize> = keys.iter().map(|k| (k.as_str(), 1)).collect();
+            let table = as_table(&to_value_with(&lua, &map, options.clone()).unwrap());
+            assert!(table.get_metatable().is_none());
+            let order: Vec<String> = table
+                .pairs::<String, usize>()
+                .map(|pair| pair.unwrap().0)
+                .collect();
+            assert_eq!(keys.len(), order.len());
+            match &expected {
+                None => expected = Some(order),
+                Some(expected) => assert_eq!(expected, &order),
+            }
+        }
+    }
+
+    #[test]
+    fn dedup_equal_values() {
+        #[derive(Serialize)]
+        struct Point {
+            x: i32,
+            tags: Vec<&'static str>,
+        }
+
+        let lua = Lua::new();
+        let points = vec![
+            Point {
+                x: 1,
+                tags: vec!["a"],
+            },
+            Point {
+                x: 2,
+                tags: vec!["a"],
+            },
+            Point {
+                x: 1,
+                tags: vec!["a"],
+            },
+        ];
+        fn elements(value: Value) -> Vec<Value> {
+            as_table(&value)
+                .sequence_values()
+                .collect::<mlua::Result<_>>()
+                .unwrap()
+        }
+
+        let options = SerializerOptions {
+            dedup_equal_values: true,
+            ..Default::default()
+        };
+        let deduped = elements(to_value_with(&lua, &points, options).unwrap());
+        assert_eq!(deduped[0], deduped[2]);
+        assert_ne!(deduped[0], deduped[1]);
+        fn tags<'lua>(value: &Value<'lua>) -> Value<'lua> {
+            as_table(value).get("tags").unwrap()
+        }
+        assert_eq!(tags(&deduped[0]), tags(&deduped[1]));
+
+        let plain = elements(to_value(&lua, &points).unwrap());
+        assert_ne!(plain[0], plain[2]);
+    }
+
+    #[test]
+    fn json_null_round_trip() {
+        let lua = Lua::new();
+        let json = serde_json::json!({ "a": null, "b": 1, "c": { "d": null } });
+        let options = SerializerOptions {
+            none_placeholder: NonePlaceholder::Nil,
+            ..Default::default()
+        };
+        let value = to_value_with(&lua, &json, options).unwrap();
+        assert_eq!(NULL, as_table(&value).get::<_, Value>("a").unwrap());
+        let de_options = DeserializerOptions {
+            null_sentinels: vec![NULL],
+            ..Default::default()
+        };
+        let back: serde_json::Value = from_value_with(value, de_options).unwrap();
+        assert_eq!(json, back);
+
+        let value = to_value(&lua, &json).unwrap();
+        let back: serde_json::Value = from_value(value).unwrap();
+        assert!(back.get("a").is_none());
+    }
+
+    #[test]
+    fn preserve_seq_holes() {
+        let lua = Lua::new();
+        let input = vec![Some(1i64), None, Some(3)];
+        let options = SerializerOptions {
+            preserve_seq_holes: true,
+            ..Default::default()
+        };
+        let value = to_value_with(&lua, &input, options.clone()).unwrap();
+        assert_eq!(3, as_table(&value).raw_len());
+        let de_options = DeserializerOptions {
+            null_sentinels: vec![NULL],
+            ..Default::default()
+        };
+        let back: Vec<Option<i64>> = from_value_with(value, de_options.clone()).unwrap();
+        assert_eq!(input, back);
+
+        let value = to_value(&lua, &input).unwrap();
+        let back: Vec<Option<i64>> = from_value(value).unwrap();
+        assert_eq!(vec![Some(1)], back);
+
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        enum Span {
+            Range(Option<i64>, Option<i64>, Option<i64>),
+        }
+        let span = Span::Range(Some(1), None, Some(3));
+        let value = to_value_with(&lua, &span, options).unwrap();
+        let range = as_table(&value).get::<_, Table>("Range").unwrap();
+        assert_eq!(3, range.raw_len());
+        assert_eq!(span, from_value_with(value, de_options).unwrap());
+    }
+
+    #[test]
+    fn reject_sparse_int_keys() {
+        let lua = Lua::new();
+        let options = SerializerOptions {
+            reject_sparse_int_keys: true,
+            ..Default::default()
+        };
+
+        let mut sparse = HashMap::new();
+        sparse.insert(1, "a");
+        sparse.insert(3, "c");
+        let err = to_value_with(&lua, &sparse, options.clone()).unwrap_err();
+        assert_eq!(
+            "sparse integer map keys, expected key 2 but found 3",
+            err.to_string()
+        );
+        assert!(to_value(&lua, &sparse).is_ok());
+
+        sparse.insert(2, "b");
+        assert!(to_value_with(&lua, &sparse, options.clone()).is_ok());
+    }
+
+    #[test]
+    fn collect_str() {
+        use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+        use std::path::PathBuf;
+
+        let lua = Lua::new();
+        let addrs = vec![
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ];
+        let value = to_value(&lua, &addrs).unwrap();
+        assert_eq!(
+            vec!["127.0.0.1".to_string(), "::1".to_string()],
+            from_value::<Vec<String>>(value.clone()).unwrap()
+        );
+        assert_eq!(addrs, from_value::<Vec<IpAddr>>(value).unwrap());
+
+        let path = PathBuf::from("/etc/app/config.lua");
+        let value = to_value(&lua, &path).unwrap();
+        assert_eq!("/etc/app/config.lua", from_value::<String>(value).unwrap());
+    }
+
+    #[test]
+    fn structs_as_userdata() {
+        use super::StructHandle;
+
+        #[derive(Serialize)]
+        struct Secret {
+            token: &'static str,
+        }
+
+        #[derive(Serialize)]
+        struct Config {
+            name: &'static str,
+            secret: Secret,
+        }
+
+        let lua = Lua::new();
+        let options = SerializerOptions {
+            structs_as_userdata: vec!["Secret".to_string()].into_iter().collect(),
+            ..Default::default()
+        };
+        let value = to_value_with(
+            &lua,
+            Config {
+                name: "app",
+                secret: Secret { token: "xyz" },
+            },
+            options,
+        )
+        .unwrap();
+
+        let secret = as_table(&value).get::<_, Value>("secret").unwrap();
+        let userdata = match &secret {
+            Value::UserData(ud) => ud,
+            _ => panic!("expected userdata, got {:?}", secret),
+        };
+        assert_eq!("Secret", userdata.borrow::<StructHandle>().unwrap().name());
+        let table = StructHandle::table(userdata).unwrap();
+        assert_eq!("xyz", table.get::<_, String>("token").unwrap());
+
+        lua.globals().set("config", value.clone()).unwrap();
+        assert_eq!(
+            "app",
+            lua.load("return config.name").eval::<String>().unwrap()
+        );
+        assert!(lua.load("return config.secret.token").exec().is_err());
+        assert!(lua.load("config.secret.token = 'abc'").exec().is_err());
+    }
+
+    #[test]
+    fn key_transform() {
+        use super::{to_value_with_key_transform, to_value_with_key_transform_and_options};
+
+        #[derive(Serialize)]
+        struct Flag {
+            on: bool,
+        }
+
+        #[derive(Serialize)]
+        struct Server {
+            host: &'static str,
+            labels: HashMap<&'static str, &'static str>,
+        }
+
+        #[derive(Serialize)]
+        struct Config {
+            name: &'static str,
+            server: Server,
+        }
+
+        let lua = Lua::new();
+        let mut labels = HashMap::new();
+        labels.insert("env", "prod");
+        let config = Config {
+            name: "app",
+            server: Server {
+                host: "localhost",
+                labels,
+            },
+        };
+        let value = to_value_with_key_transform(&lua, &config, |key| key.to_uppercase()).unwrap();
+        let table = as_table(&value);
+        assert_eq!("app", table.get::<_, String>("NAME").unwrap());
+        let server: Table = table.get("SERVER").unwrap();
+        assert_eq!("localhost", server.get::<_, String>("HOST").unwrap());
+        let labels: Table = server.get("LABELS").unwrap();
+        assert_eq!("prod", labels.get::<_, String>("ENV").unwrap());
+        assert_eq!(Value::Nil, table.get::<_, Value>("name").unwrap());
+
+        let options = SerializerOptions {
+            bool_as_int: true,
+            ..Default::default()
+        };
+        let value = to_value_with_key_transform_and_options(
+            &lua,
+            &Flag { on: true },
+            str::to_uppercase,
+            options,
+        )
+        .unwrap();
+        assert_eq!(1, as_table(&value).get::<_, i64>("ON").unwrap());
+    }
+
+    #[test]
+    fn int_key_offset_round_trip() {
+        let lua = Lua::new();
+        let mut slots = HashMap::new();
+        slots.insert(0u32, "sword".to_string());
+        slots.insert(1u32, "shield".to_string());
+
+        let value = to_value_with(
+            &lua,
+            &slots,
+            SerializerOptions {
+                int_key_offset: 1,
+                reject_sparse_int_keys: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        lua.globals().set("slots", value.clone()).unwrap();
+        assert_eq!(
+            "sword,shield",
+            lua.load("return table.concat(slots, ',')")
+                .eval::<String>()
+                .unwrap()
+        );
+
+        let options = DeserializerOptions {
+            int_key_offset: 1,
+            ..Default::default()
+        };
+        let back: HashMap<u32, String> = from_value_with(value, options).unwrap();
+        assert_eq!(slots, back);
+    }
+
+    #[test]
+    fn human_readable() {
+        use std::net::{IpAddr, Ipv4Addr};
+
+        let lua = Lua::new();
+        let addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let value = to_value(&lua, addr).unwrap();
+        assert_eq!("127.0.0.1", from_value::<String>(value).unwrap());
+
+        let options = SerializerOptions {
+            human_readable: false,
+            ..Default::default()
+        };
+        let value = to_value_with(&lua, addr, options).unwrap();
+        let octets: HashMap<String, Vec<u8>> = from_value(value.clone()).unwrap();
+        assert_eq!(Some(&vec![127, 0, 0, 1]), octets.get("V4"));
+
+        let options = DeserializerOptions {
+            human_readable: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            addr,
+            from_value_with::<IpAddr>(value.clone(), options).unwrap()
+        );
+        assert!(from_value::<IpAddr>(value).is_err());
+    }
+
+    #[test]
+    fn shared_string_cache() {
+        use super::SharedStringCache;
+
+        #[derive(Serialize)]
+        enum Level {
+            Warning,
+        }
+
+        let lua = Lua::new();
+        let cache = SharedStringCache::new();
+        let options = SerializerOptions {
+            string_cache: Some(cache.clone()),
+            ..Default::default()
+        };
+        let a = to_value_with(&lua, Level::Warning, options.clone()).unwrap();
+        let b = to_value_with(&lua, vec![Level::Warning], options).unwrap();
+        assert_eq!(1, cache.len());
+
+        lua.globals().set("a", a).unwrap();
+        lua.globals().set("b", b).unwrap();
+        assert!(lua.load("return rawequal(a, b[1])").eval::<bool>().unwrap());
+    }
+
+    #[test]
+    fn empty_string_as_nil() {
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct User {
+            name: String,
+            #[serde(default)]
+            nickname: String,
+        }
+
+        let lua = Lua::new();
+        let user = User {
+            name: "ann".to_string(),
+            nickname: String::new(),
+        };
+        let value = to_value(&lua, &user).unwrap();
+        assert_eq!("", as_table(&value).get::<_, String>("nickname").unwrap());
+
+        let options = SerializerOptions {
+            empty_string_as_nil: true,
+            ..Default::default()
+        };
+        let value = to_value_with(&lua, &user, options.clone()).unwrap();
+        let table = as_table(&value);
+        assert_eq!(Value::Nil, table.get::<_, Value>("nickname").unwrap());
+        assert_eq!("ann", table.get::<_, String>("name").unwrap());
+        let de_options = DeserializerOptions {
+            nil_as_empty_string: true,
+            ..Default::default()
+        };
+        assert_eq!(user, from_value_with(value, de_options.clone()).unwrap());
+
+        let value = to_value_with(&lua, "", options.clone()).unwrap();
+        assert_eq!(Value::Nil, value);
+        assert_eq!(
+            "",
+            from_value_with::<String>(value, de_options.clone()).unwrap()
+        );
+        assert!(from_value::<String>(Value::Nil).is_err());
+
+        // sequence elements stay strings, so that the sequence keeps its length
+        let names = vec!["a", "", "b"];
+        let value = to_value_with(&lua, &names, options).unwrap();
+        assert_eq!(3, as_table(&value).raw_len());
+        assert_eq!(
+            names,
+            from_value_with::<Vec<String>>(value, de_options).unwrap()
+        );
+    }
+
+    #[test]
+    fn set_serialized_fields() {
+        let lua = Lua::new();
+        let table = lua.create_table().unwrap();
+        set_serialized(&lua, &table, "name", "app").unwrap();
+        set_serialized(&lua, &table, "ports", &[80u16, 443]).unwrap();
+        set_serialized(&lua, &table, 1, &Some(1.5)).unwrap();
+        let config = Config {
+            name: "db",
+            timeout: Some(30),
+        };
+        set_serialized(&lua, &table, "config", &config).unwrap();
+        lua.globals().set("t", table).unwrap();
+
+        let (name, port, one, timeout): (String, u16, f64, u32) = lua
+            .load("return t.name, t.ports[2], t[1], t.config.timeout")
+            .eval()
+            .unwrap();
+        assert_eq!(
+            ("app".to_string(), 443, 1.5, 30),
+            (name, port, one, timeout)
+        );
+
+        let options = Rc::new(SerializerOptions {
+            bool_as_int: true,
+            ..Default::default()
+        });
+        let table = lua.create_table().unwrap();
+        set_serialized_with(&lua, &table, "on", &true, &options).unwrap();
+        assert_eq!(1, table.get::<_, i64>("on").unwrap());
+    }
+
+    #[test]
+    fn serialize_lazy_iter() {
+        let lua = Lua::new();
+        let value = serialize_iter(&lua, (1..=1000).map(|i| i * 2)).unwrap();
+        let table = as_table(&value);
+        assert_eq!(1000, table.raw_len());
+        for (i, v) in table.sequence_values::<i64>().enumerate() {
+            assert_eq!((i as i64 + 1) * 2, v.unwrap());
+        }
+
+        let value = serialize_iter(&lua, std::iter::empty::<Config>()).unwrap();
+        assert!(matches!(value, Value::Table(t) if t.raw_len() == 0));
+    }
+
+    #[test]
+    fn stringify_map_keys() {
+        let lua = Lua::new();
+        let options = SerializerOptions {
+            stringify_map_keys: true,
+            ..Default::default()
+        };
+
+        let mut flags = HashMap::new();
+        flags.insert(true, 1);
+        flags.insert(false, 0);
+        let value = to_value_with(&lua, &flags, options.clone()).unwrap();
+        let table = as_table(&value);
+        assert_eq!(1, table.get::<_, i32>("true").unwrap());
+        assert_eq!(0, table.get::<_, i32>("false").unwrap());
+        assert_eq!(Value::Nil, table.get::<_, Value>(true).unwrap());
+
+        let mut grades = HashMap::new();
+        grades.insert('a', 90);
+        grades.insert('b', 80);
+        let value = to_value_with(&lua, &grades, options.clone()).unwrap();
+        let table = as_table(&value);
+        assert_eq!(90, table.get::<_, i32>("a").unwrap());
+        assert_eq!(80, table.get::<_, i32>("b").unwrap());
+
+        let mut ids = HashMap::new();
+        ids.insert(7, "x");
+        let value = to_value_with(&lua, &ids, options).unwrap();
+        let table = as_table(&value);
+        assert_eq!("x", table.get::<_, String>("7").unwrap());
+
+        // without the option, the keys keep their type
+        let value = to_value(&lua, &flags).unwrap();
+        let table = as_table(&value);
+        assert_eq!(1, table.get::<_, i32>(true).unwrap());
+    }
+
+    #[test]
+    fn options_are_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SerializerOptions>();
     }
 }
@@ -0,0 +1,67 @@
+//! Serialize and deserialize an [`OffsetDateTime`] as an RFC 3339 Lua string, e.g.
+//! `"2020-09-13T12:26:40Z"`.
+//!
+//! ```ignore
+//! #[derive(Serialize, Deserialize)]
+//! struct Event {
+//!     #[serde(with = "serde_mlua::time_rfc3339")]
+//!     at: OffsetDateTime,
+//! }
+//! ```
+
+use serde::{de, ser, Deserialize, Deserializer, Serializer};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+pub fn serialize<S>(time: &OffsetDateTime, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let s = time.format(&Rfc3339).map_err(ser::Error::custom)?;
+    serializer.serialize_str(&s)
+}
+
+pub fn deserialize<'de, D>(deserializer: D) -> Result<OffsetDateTime, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    OffsetDateTime::parse(&s, &Rfc3339).map_err(de::Error::custom)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{from_value, to_value};
+    use mlua::{Lua, Value};
+    use serde::{Deserialize, Serialize};
+    use time::OffsetDateTime;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Event {
+        #[serde(with = "super")]
+        at: OffsetDateTime,
+    }
+
+    #[test]
+    fn round_trip() {
+        let lua = Lua::new();
+        let event = Event {
+            at: OffsetDateTime::from_unix_timestamp(1_600_000_000).unwrap(),
+        };
+        let value = to_value(&lua, &event).unwrap();
+        match &value {
+            Value::Table(t) => {
+                assert_eq!("2020-09-13T12:26:40Z", t.get::<_, String>("at").unwrap())
+            }
+            _ => panic!("expected table"),
+        }
+        assert_eq!(event, from_value::<Event>(value).unwrap());
+    }
+
+    #[test]
+    fn invalid_string() {
+        let lua = Lua::new();
+        let value = lua.load(r#"return { at = "yesterday" }"#).eval().unwrap();
+        assert!(from_value::<Event>(value).is_err());
+    }
+}
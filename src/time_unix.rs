@@ -0,0 +1,123 @@
+//! Serialize and deserialize an [`OffsetDateTime`] as a Lua number of seconds since the Unix
+//! epoch (UTC). Timestamps with a fractional second are written as floats, which are only
+//! precise to a few hundred nanoseconds at current dates.
+//!
+//! ```ignore
+//! #[derive(Serialize, Deserialize)]
+//! struct Event {
+//!     #[serde(with = "serde_mlua::time_unix")]
+//!     at: OffsetDateTime,
+//! }
+//! ```
+
+use serde::de::{self, Visitor};
+use serde::{Deserializer, Serializer};
+use std::convert::TryFrom;
+use std::fmt;
+use time::{Duration, OffsetDateTime};
+
+pub fn serialize<S>(time: &OffsetDateTime, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    if time.nanosecond() == 0 {
+        serializer.serialize_i64(time.unix_timestamp())
+    } else {
+        let nanos = f64::from(time.nanosecond()) / 1e9;
+        serializer.serialize_f64(time.unix_timestamp() as f64 + nanos)
+    }
+}
+
+pub fn deserialize<'de, D>(deserializer: D) -> Result<OffsetDateTime, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_any(UnixVisitor)
+}
+
+struct UnixVisitor;
+
+impl<'de> Visitor<'de> for UnixVisitor {
+    type Value = OffsetDateTime;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("seconds since the Unix epoch")
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<OffsetDateTime, E>
+    where
+        E: de::Error,
+    {
+        OffsetDateTime::from_unix_timestamp(v).map_err(de::Error::custom)
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<OffsetDateTime, E>
+    where
+        E: de::Error,
+    {
+        let v = i64::try_from(v).map_err(de::Error::custom)?;
+        self.visit_i64(v)
+    }
+
+    // Whole seconds and nanoseconds are taken separately, so that only the precision of `v`
+    // itself is lost.
+    fn visit_f64<E>(self, v: f64) -> Result<OffsetDateTime, E>
+    where
+        E: de::Error,
+    {
+        if !v.is_finite() {
+            return Err(de::Error::invalid_value(de::Unexpected::Float(v), &self));
+        }
+        let secs = v.floor();
+        let nanos = ((v - secs) * 1e9).round() as i64;
+        OffsetDateTime::from_unix_timestamp(secs as i64)
+            .map_err(de::Error::custom)?
+            .checked_add(Duration::nanoseconds(nanos))
+            .ok_or_else(|| de::Error::invalid_value(de::Unexpected::Float(v), &self))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{from_value, to_value};
+    use mlua::{Lua, Value};
+    use serde::{Deserialize, Serialize};
+    use time::OffsetDateTime;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Event {
+        #[serde(with = "super")]
+        at: OffsetDateTime,
+    }
+
+    #[test]
+    fn round_trip() {
+        let lua = Lua::new();
+        let event = Event {
+            at: OffsetDateTime::from_unix_timestamp(1_600_000_000).unwrap(),
+        };
+        let value = to_value(&lua, &event).unwrap();
+        match &value {
+            Value::Table(t) => assert_eq!(1_600_000_000, t.get::<_, i64>("at").unwrap()),
+            _ => panic!("expected table"),
+        }
+        assert_eq!(event, from_value::<Event>(value).unwrap());
+
+        let value = lua.load("return { at = 1600000000.5 }").eval().unwrap();
+        let event: Event = from_value(value).unwrap();
+        assert_eq!(500_000_000, event.at.nanosecond());
+    }
+
+    #[test]
+    fn fractional_precision() {
+        let lua = Lua::new();
+        for &nanos in &[1_600_000_000_123_456_789i128, -1_500_000_000] {
+            let event = Event {
+                at: OffsetDateTime::from_unix_timestamp_nanos(nanos).unwrap(),
+            };
+            let value = to_value(&lua, &event).unwrap();
+            let back = from_value::<Event>(value).unwrap().at;
+            assert!((back - event.at).abs() < time::Duration::microseconds(1));
+        }
+    }
+}
@@ -0,0 +1,68 @@
+//! Serialize and deserialize a [`Uuid`] as its canonical hyphenated Lua string, regardless of
+//! the `uuid` crate's serde representation.
+//!
+//! ```ignore
+//! #[derive(Serialize, Deserialize)]
+//! struct Entity {
+//!     #[serde(with = "serde_mlua::uuid_as_string")]
+//!     id: Uuid,
+//! }
+//! ```
+
+use serde::{de, Deserialize, Deserializer, Serializer};
+use uuid::Uuid;
+
+pub fn serialize<S>(uuid: &Uuid, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(
+        uuid.to_hyphenated_ref()
+            .encode_lower(&mut Uuid::encode_buffer()),
+    )
+}
+
+pub fn deserialize<'de, D>(deserializer: D) -> Result<Uuid, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    Uuid::parse_str(&s).map_err(de::Error::custom)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{from_value, to_value};
+    use mlua::{Lua, Value};
+    use serde::{Deserialize, Serialize};
+    use uuid::Uuid;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Entity {
+        #[serde(with = "super")]
+        id: Uuid,
+    }
+
+    #[test]
+    fn round_trip() {
+        let lua = Lua::new();
+        let entity = Entity {
+            id: Uuid::parse_str("936da01f-9abd-4d9d-80c7-02af85c822a8").unwrap(),
+        };
+        let value = to_value(&lua, &entity).unwrap();
+        let id = match &value {
+            Value::Table(t) => t.get::<_, String>("id").unwrap(),
+            _ => panic!("expected table"),
+        };
+        assert_eq!("936da01f-9abd-4d9d-80c7-02af85c822a8", id);
+        let result: Entity = from_value(value).unwrap();
+        assert_eq!(entity, result);
+    }
+
+    #[test]
+    fn invalid_string() {
+        let lua = Lua::new();
+        let value = lua.load(r#"return { id = "not-a-uuid" }"#).eval().unwrap();
+        assert!(from_value::<Entity>(value).is_err());
+    }
+}